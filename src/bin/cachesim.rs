@@ -0,0 +1,196 @@
+use papers_web_love::caching::opt::Belady;
+use papers_web_love::caching::sim::{self, Access, Report, Simulator, Stats};
+use papers_web_love::caching::trace::{self, Format};
+use std::env;
+use std::process;
+
+const USAGE: &str = "Usage: cachesim [OPTIONS] <TRACE>
+
+Replays a trace against cache policies and prints their hit ratios.
+
+Options:
+    -f, --format <FORMAT>      arc, lirs, spc, umass, msr or simple [default: simple]
+    -p, --policies <LIST>      comma separated policies [default: all but opt]
+    -c, --capacity <RANGE>     N or START:END[:STEP] in entries, or in bytes for
+                               gdsf, STEP defaults to START [default: 1000]
+        --csv                  print CSV instead of a table
+    -h, --help                 print this message
+
+opt is Belady's offline optimum, it is only run when listed.
+
+Policies:";
+
+struct Options {
+    trace: String,
+    format: Format,
+    policies: Vec<String>,
+    capacities: Vec<usize>,
+    csv: bool,
+}
+
+fn usage() -> String {
+    let mut s = USAGE.to_string();
+    for name in sim::BUILTIN.iter().chain(&["opt"]) {
+        s.push(' ');
+        s.push_str(name);
+    }
+    s
+}
+
+fn parse_capacities(s: &str) -> Result<Vec<usize>, String> {
+    let invalid = || format!("invalid capacity range: {}", s);
+    let nums = s
+        .split(':')
+        .map(|n| n.parse::<usize>().map_err(|_| invalid()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (start, end, step) = match nums[..] {
+        [n] => (n, n, 1),
+        [start, end] => (start, end, start),
+        [start, end, step] => (start, end, step),
+        _ => return Err(invalid()),
+    };
+    if start == 0 || step == 0 || end < start {
+        return Err(invalid());
+    }
+    Ok((start..=end).step_by(step).collect())
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut opts = Options {
+        trace: String::new(),
+        format: Format::Simple,
+        policies: vec![],
+        capacities: vec![1000],
+        csv: false,
+    };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = |name: &str| {
+            iter.next()
+                .cloned()
+                .ok_or_else(|| format!("missing value for {}", name))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => return Err(String::new()),
+            "-f" | "--format" => opts.format = value(arg)?.parse()?,
+            "-p" | "--policies" => {
+                opts.policies = value(arg)?.split(',').map(str::to_string).collect()
+            }
+            "-c" | "--capacity" => opts.capacities = parse_capacities(&value(arg)?)?,
+            "--csv" => opts.csv = true,
+            _ if arg.starts_with('-') => return Err(format!("unknown option: {}", arg)),
+            _ if opts.trace.is_empty() => opts.trace = arg.clone(),
+            _ => return Err(format!("unexpected argument: {}", arg)),
+        }
+    }
+    if opts.trace.is_empty() {
+        return Err("missing trace file".to_string());
+    }
+    if opts.policies.is_empty() {
+        opts.policies = sim::BUILTIN.iter().map(|p| p.to_string()).collect();
+    }
+    Ok(opts)
+}
+
+fn run(opts: &Options) -> Result<Vec<Report>, String> {
+    let mut simulator = Simulator::new();
+    for name in opts.policies.iter().filter(|&p| p != "opt") {
+        let factory = sim::builtin(name).ok_or_else(|| format!("unknown policy: {}", name))?;
+        simulator = simulator.policy(name, factory);
+    }
+    let accesses = trace::open(&opts.trace, opts.format)
+        .and_then(|reader| reader.collect::<Result<Vec<Access<u64>>, _>>())
+        .map_err(|e| format!("{}: {}", opts.trace, e))?;
+    let mut reports = simulator.run(&accesses, &opts.capacities);
+    if opts.policies.iter().any(|p| p == "opt") {
+        let keys: Vec<u64> = accesses.iter().map(|a| a.key).collect();
+        let belady = Belady::new(&keys);
+        for &capacity in opts.capacities.iter() {
+            let opt = belady.simulate(capacity);
+            reports.push(Report {
+                policy: "opt".to_string(),
+                capacity,
+                stats: Stats {
+                    requests: opt.requests,
+                    hits: opt.hits,
+                    ..Stats::default()
+                },
+            });
+        }
+    }
+    Ok(reports)
+}
+
+fn print(reports: &[Report], csv: bool) {
+    if csv {
+        println!("policy,capacity,requests,hit_ratio,byte_hit_ratio,evictions,rejections");
+    } else {
+        println!(
+            "{:<12} {:>10} {:>12} {:>10} {:>15} {:>12} {:>12}",
+            "policy",
+            "capacity",
+            "requests",
+            "hit ratio",
+            "byte hit ratio",
+            "evictions",
+            "rejections"
+        );
+    }
+    for r in reports {
+        // OPT only knows about hits
+        let (bhr, evictions, rejections) = if r.policy == "opt" {
+            ("-".to_string(), "-".to_string(), "-".to_string())
+        } else {
+            (
+                format!("{:.4}", r.stats.byte_hit_ratio()),
+                r.stats.evictions.to_string(),
+                r.stats.rejections.to_string(),
+            )
+        };
+        if csv {
+            println!(
+                "{},{},{},{:.4},{},{},{}",
+                r.policy,
+                r.capacity,
+                r.stats.requests,
+                r.stats.hit_ratio(),
+                bhr,
+                evictions,
+                rejections
+            );
+        } else {
+            println!(
+                "{:<12} {:>10} {:>12} {:>10.4} {:>15} {:>12} {:>12}",
+                r.policy,
+                r.capacity,
+                r.stats.requests,
+                r.stats.hit_ratio(),
+                bhr,
+                evictions,
+                rejections
+            );
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let opts = match parse_args(&args) {
+        Ok(opts) => opts,
+        Err(e) if e.is_empty() => {
+            println!("{}", usage());
+            return;
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, usage());
+            process::exit(2);
+        }
+    };
+    match run(&opts) {
+        Ok(reports) => print(&reports, opts.csv),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::caching::two_q_lru::SimplifiedTwoQ;
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Number of a page in the file, page n starts at n * page size.
+pub type PageId = u64;
+
+// A frame holds one page of the file
+struct Frame {
+    data: RefCell<Box<[u8]>>,
+    page: Cell<Option<PageId>>,
+    pins: Cell<usize>,
+    dirty: Cell<bool>,
+}
+
+struct State {
+    // Resident pages and their frames
+    table: HashMap<PageId, usize>,
+    free: Vec<usize>,
+    // Replacement policy over the resident pages, the pinned ones are pinned in it
+    policy: SimplifiedTwoQ<PageId, usize>,
+    // Number of pages in the file, including new pages not written yet
+    pages: u64,
+}
+
+/// A buffer manager caching the pages of a file in a fixed number of frames.
+///
+/// Pages are accessed through guards which pin them in the 2Q policy, so a
+/// pinned page is never evicted. When a page must be read and no frame is
+/// free, the page 2Q evicts is written back if dirty and its frame reused.
+/// Dirty pages are also written by `flush_page`, `flush_all` and on drop.
+///
+/// A page can have several guards, but only one of them can borrow its data
+/// mutably at a time.
+pub struct BufferPool {
+    file: RefCell<File>,
+    page_size: usize,
+    frames: Vec<Frame>,
+    state: RefCell<State>,
+}
+
+impl BufferPool {
+    /// Create a pool of frames pages of page_size bytes over a file
+    /// opened for reading and writing.
+    pub fn new(file: File, page_size: usize, frames: usize) -> io::Result<BufferPool> {
+        assert!(page_size > 0, "pages need a size");
+        assert!(frames > 0, "BufferPool needs at least one frame");
+        let len = file.metadata()?.len();
+        let page_size_u64 = page_size as u64;
+        Ok(BufferPool {
+            file: RefCell::new(file),
+            page_size,
+            frames: (0..frames)
+                .map(|_| Frame {
+                    data: RefCell::new(vec![0; page_size].into_boxed_slice()),
+                    page: Cell::new(None),
+                    pins: Cell::new(0),
+                    dirty: Cell::new(false),
+                })
+                .collect(),
+            state: RefCell::new(State {
+                table: HashMap::new(),
+                free: (0..frames).rev().collect(),
+                policy: SimplifiedTwoQ::with_capacity(frames),
+                pages: len.div_ceil(page_size_u64),
+            }),
+        })
+    }
+
+    /// Returns the size of a page in bytes.
+    #[inline]
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Returns the number of frames.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns the number of pages of the file.
+    pub fn pages(&self) -> u64 {
+        self.state.borrow().pages
+    }
+
+    /// Returns the number of pages in the frames.
+    pub fn resident(&self) -> usize {
+        self.state.borrow().table.len()
+    }
+
+    /// Returns the number of pinned pages.
+    pub fn pinned(&self) -> usize {
+        self.state.borrow().policy.pinned_len()
+    }
+
+    /// Returns true if the page is in a frame.
+    pub fn is_resident(&self, id: PageId) -> bool {
+        self.state.borrow().table.contains_key(&id)
+    }
+
+    /// Pin a page of the file, reading it if it is not resident.
+    /// Pages past the end of the file read as zeros.
+    /// Fails if every frame is pinned.
+    pub fn fetch(&self, id: PageId) -> io::Result<PageGuard<'_>> {
+        let mut state = self.state.borrow_mut();
+        if let Some(&frame) = state.policy.get(&id) {
+            return Ok(self.pin(&mut state, frame, id));
+        }
+        let frame = self.free_frame(&mut state)?;
+        if let Err(e) = self.read_page(id, &mut self.frames[frame].data.borrow_mut()) {
+            state.free.push(frame);
+            return Err(e);
+        }
+        self.install(&mut state, frame, id);
+        state.pages = state.pages.max(id + 1);
+        Ok(self.pin(&mut state, frame, id))
+    }
+
+    /// Pin a new zeroed page at the end of the file.
+    pub fn new_page(&self) -> io::Result<PageGuard<'_>> {
+        let mut state = self.state.borrow_mut();
+        let frame = self.free_frame(&mut state)?;
+        self.frames[frame].data.borrow_mut().fill(0);
+        self.frames[frame].dirty.set(true);
+        let id = state.pages;
+        state.pages += 1;
+        self.install(&mut state, frame, id);
+        Ok(self.pin(&mut state, frame, id))
+    }
+
+    /// Write a page if it is resident and dirty. Fails with `WouldBlock` if
+    /// a guard is borrowing its data mutably, the page then stays dirty.
+    pub fn flush_page(&self, id: PageId) -> io::Result<()> {
+        let frame = self.state.borrow().table.get(&id).cloned();
+        match frame {
+            Some(frame) => self.write_back(frame),
+            None => Ok(()),
+        }
+    }
+
+    /// Write all dirty pages and sync the file. The pages whose data a guard
+    /// is borrowing mutably are skipped, and then it fails with `WouldBlock`.
+    pub fn flush_all(&self) -> io::Result<()> {
+        let mut skipped = 0;
+        for frame in 0..self.frames.len() {
+            match self.write_back(frame) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => skipped += 1,
+                result => result?,
+            }
+        }
+        self.file.borrow().sync_data()?;
+        if skipped > 0 {
+            let msg = format!("{} pages are being written", skipped);
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, msg));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn pin(&self, state: &mut State, frame: usize, id: PageId) -> PageGuard<'_> {
+        let f = &self.frames[frame];
+        if f.pins.get() == 0 {
+            state.policy.pin(&id);
+        }
+        f.pins.set(f.pins.get() + 1);
+        PageGuard {
+            pool: self,
+            frame,
+            id,
+        }
+    }
+
+    fn install(&self, state: &mut State, frame: usize, id: PageId) {
+        self.frames[frame].page.set(Some(id));
+        state.table.insert(id, frame);
+        state.policy.insert(id, frame);
+    }
+
+    // Take a free frame, or evict the page 2Q would evict
+    fn free_frame(&self, state: &mut State) -> io::Result<usize> {
+        if let Some(frame) = state.free.pop() {
+            return Ok(frame);
+        }
+        let victim = match state.policy.victim() {
+            Some(&id) => id,
+            None => return Err(io::Error::other("all pages are pinned")),
+        };
+        let frame = state.table[&victim];
+        self.write_back(frame)?;
+        state.policy.remove(&victim);
+        state.table.remove(&victim);
+        self.frames[frame].page.set(None);
+        Ok(frame)
+    }
+
+    fn write_back(&self, frame: usize) -> io::Result<()> {
+        let f = &self.frames[frame];
+        let id = match f.page.get() {
+            Some(id) if f.dirty.get() => id,
+            _ => return Ok(()),
+        };
+        // Being written through a guard, it stays dirty
+        let data = match f.data.try_borrow() {
+            Ok(data) => data,
+            Err(_) => {
+                let msg = format!("page {} is being written", id);
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, msg));
+            }
+        };
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(id * self.page_size as u64))?;
+        file.write_all(&data)?;
+        f.dirty.set(false);
+        Ok(())
+    }
+
+    fn read_page(&self, id: PageId, buf: &mut [u8]) -> io::Result<()> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(id * self.page_size as u64))?;
+        let mut read = 0;
+        while read < buf.len() {
+            match file.read(&mut buf[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        buf[read..].fill(0);
+        Ok(())
+    }
+}
+
+impl Drop for BufferPool {
+    fn drop(&mut self) {
+        let _ = self.flush_all();
+    }
+}
+
+/// A pinned page, unpinned when the guard is dropped.
+pub struct PageGuard<'a> {
+    pool: &'a BufferPool,
+    frame: usize,
+    id: PageId,
+}
+
+impl<'a> PageGuard<'a> {
+    /// Returns the number of the page.
+    #[inline]
+    pub fn id(&self) -> PageId {
+        self.id
+    }
+
+    /// Borrow the content of the page.
+    pub fn data(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.pool.frames[self.frame].data.borrow(), |d| &**d)
+    }
+
+    /// Borrow the content of the page mutably, marking it dirty.
+    pub fn data_mut(&mut self) -> RefMut<'_, [u8]> {
+        let f = &self.pool.frames[self.frame];
+        f.dirty.set(true);
+        RefMut::map(f.data.borrow_mut(), |d| &mut **d)
+    }
+
+    /// Returns true if the page was modified since it was last written.
+    pub fn is_dirty(&self) -> bool {
+        self.pool.frames[self.frame].dirty.get()
+    }
+}
+
+impl<'a> Drop for PageGuard<'a> {
+    fn drop(&mut self) {
+        let f = &self.pool.frames[self.frame];
+        f.pins.set(f.pins.get() - 1);
+        if f.pins.get() == 0 {
+            self.pool.state.borrow_mut().policy.unpin(&self.id);
+        }
+    }
+}
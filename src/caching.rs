@@ -7,3 +7,217 @@ pub mod lfu;
 /// by Theodore Johnson and Dennis Shasha
 /// [Paper](http://www.vldb.org/conf/1994/P439.PDF)
 pub mod two_q_lru;
+
+/// Second-Level Buffer Cache Management
+/// by Yuanyuan Zhou, Zhifeng Chen and Kai Li
+/// [Paper](https://www.usenix.org/legacy/event/usenix01/full_papers/zhou/zhou.pdf)
+pub mod mq;
+
+/// Improving WWW Proxies Performance with Greedy-Dual-Size-Frequency Caching Policy
+/// by Ludmila Cherkasova
+/// [Paper](https://www.hpl.hp.com/techreports/98/HPL-98-69R1.pdf)
+pub mod gdsf;
+
+/// A study of replacement algorithms for a virtual-storage computer
+/// by L. A. Belady
+/// [Paper](https://doi.org/10.1147/sj.52.0078)
+pub mod opt;
+
+/// Common interface of the cache policies in this crate.
+pub trait Cache<K, V> {
+    /// Get value with key, counting it as an access.
+    fn get(&mut self, k: &K) -> Option<&V>;
+
+    /// Get value with key, without counting it as an access.
+    fn peek(&self, k: &K) -> Option<&V>;
+
+    /// Insert K-V pair to the cache.
+    /// Returns the entry evicted to make room for it, if any.
+    /// A new entry the cache does not take is dropped, which includes a cache
+    /// whose entries are all pinned. Callers that pin entries must use
+    /// `try_insert` to get the entry back.
+    fn insert(&mut self, k: K, v: V) -> Option<(K, V)>;
+
+    /// Insert K-V pair to the cache, telling an eviction from a rejection
+    /// of the new entry. Fails if the cache is full and every entry is pinned.
+    /// An entry that is not handed back is in the cache afterwards.
+    fn try_insert(&mut self, k: K, v: V) -> Result<Insertion<K, V>, AllPinned<K, V>> {
+        Ok(match self.insert(k, v) {
+            Some((k, v)) => Insertion::Evicted(k, v),
+            None => Insertion::Inserted,
+        })
+    }
+
+    /// Insert K-V pair of the given size, as `try_insert`. Policies that
+    /// weigh entries by size count it against their capacity, the others
+    /// ignore it. Making room may evict several entries, the last is returned.
+    fn try_insert_sized(
+        &mut self,
+        k: K,
+        v: V,
+        size: u64,
+    ) -> Result<Insertion<K, V>, AllPinned<K, V>> {
+        let _ = size;
+        self.try_insert(k, v)
+    }
+
+    /// Insert an entry read through the cache, returns its value, borrowed
+    /// from the cache or handed back if the cache does not keep it, and the
+    /// entry evicted to make room for it.
+    fn insert_loaded(&mut self, k: K, v: V) -> (Loaded<'_, V>, Option<(K, V)>)
+    where
+        K: Clone,
+    {
+        let key = k.clone();
+        match self.try_insert(k, v) {
+            Ok(Insertion::Rejected(_, v)) | Err(AllPinned(_, v)) => (Loaded::Uncached(v), None),
+            Ok(insertion) => {
+                let evicted = insertion.evicted();
+                let v = self.peek(&key).expect("try_insert kept the entry");
+                (Loaded::Cached(v), evicted)
+            }
+        }
+    }
+
+    /// Remove an entry from the cache, returns its value.
+    fn remove(&mut self, k: &K) -> Option<V>;
+
+    /// Returns true if the key is cached, without counting it as an access.
+    fn contains(&self, k: &K) -> bool;
+
+    /// Returns the number of elements in the cache.
+    fn len(&self) -> usize;
+
+    /// Returns true if the cache contains no elements.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of elements the cache can hold.
+    fn capacity(&self) -> usize;
+
+    /// Remove all data in the cache.
+    fn clear(&mut self);
+}
+
+/// Outcome of `try_insert`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Insertion<K, V> {
+    /// The entry was inserted without evicting another one.
+    Inserted,
+    /// The entry was inserted, and this entry was evicted to make room for it.
+    Evicted(K, V),
+    /// The cache refused the entry, by its admission policy or for lack of
+    /// capacity, and handed it back.
+    Rejected(K, V),
+}
+
+impl<K, V> Insertion<K, V> {
+    /// Returns the evicted entry, if any.
+    pub fn evicted(self) -> Option<(K, V)> {
+        match self {
+            Insertion::Evicted(k, v) => Some((k, v)),
+            _ => None,
+        }
+    }
+}
+
+/// A value read through a cache: borrowed from the cache, or owned by the
+/// caller when the cache does not keep it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Loaded<'a, V> {
+    /// The value is in the cache.
+    Cached(&'a V),
+    /// The cache did not keep the value.
+    Uncached(V),
+}
+
+impl<V> std::ops::Deref for Loaded<'_, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        match self {
+            Loaded::Cached(v) => v,
+            Loaded::Uncached(v) => v,
+        }
+    }
+}
+
+impl<V: Clone> Loaded<'_, V> {
+    /// Returns the value, cloned if it is in the cache.
+    pub fn into_owned(self) -> V {
+        match self {
+            Loaded::Cached(v) => v.clone(),
+            Loaded::Uncached(v) => v,
+        }
+    }
+}
+
+/// Error of `try_insert` when the cache is full and every entry is pinned,
+/// with the entry that could not be inserted.
+pub struct AllPinned<K, V>(pub K, pub V);
+
+impl<K, V> std::fmt::Debug for AllPinned<K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AllPinned { .. }")
+    }
+}
+
+impl<K, V> std::fmt::Display for AllPinned<K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("every entry of the cache is pinned")
+    }
+}
+
+impl<K, V> std::error::Error for AllPinned<K, V> {}
+
+/// Trace-driven simulation of the policies above.
+pub mod sim;
+
+/// Readers of published cache trace formats.
+pub mod trace;
+
+/// Evaluation techniques for storage hierarchies
+/// by R. L. Mattson, J. Gecsei, D. R. Slutz and I. L. Traiger
+/// [Paper](https://doi.org/10.1147/sj.92.0078)
+pub mod mrc;
+
+/// Efficient MRC Construction with SHARDS
+/// by Carl A. Waldspurger, Nohhyun Park, Alexander Garthwaite and Irfan Ahmad
+/// [Paper](https://www.usenix.org/system/files/conference/fast15/fast15-paper-waldspurger.pdf)
+pub mod shards;
+
+/// Versioned binary snapshots of the policies above.
+pub mod snapshot;
+
+/// Write-ahead logs of cache mutations, replayed on top of a snapshot.
+pub mod wal;
+
+/// Read-through caches filled by a loader.
+pub mod loading;
+
+/// Caches shared between threads.
+pub mod sync;
+
+/// Read-through caches with asynchronous loaders.
+pub mod async_loading;
+
+/// Write-back caches in front of a backing store.
+pub mod writeback;
+
+/// Caches invalidating groups of tagged entries.
+pub mod tagged;
+
+/// Caches remembering the keys without a value.
+pub mod negative;
+
+/// Two-level caches, a memory tier in front of a disk tier.
+pub mod tiered;
+
+/// Log-structured stores of entries in segment files.
+pub mod segments;
+
+/// TinyLFU: A Highly Efficient Cache Admission Policy
+/// by Gil Einziger, Roy Friedman and Ben Manes
+/// [Paper](https://arxiv.org/abs/1512.00727)
+pub mod admission;
@@ -0,0 +1,91 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::common::bloom::BloomFilter;
+use crate::common::sketch::CountMinSketch;
+use std::hash::Hash;
+
+/// Decides whether a new entry may take the place of the entry the eviction
+/// policy of a full cache would evict.
+///
+/// Caches record the hits and the inserts, then ask `admit` before an insert
+/// that needs an eviction. A rejected entry is not inserted.
+pub trait AdmissionPolicy<K> {
+    /// Record an access to a key, a hit or an insert.
+    fn record(&mut self, k: &K);
+
+    /// Returns true if the candidate should replace the victim.
+    fn admit(&mut self, candidate: &K, victim: &K) -> bool;
+}
+
+/// TinyLFU
+/// on an access to key k:
+///      increment the counters of k in the sketch
+///      after W increments, halve all counters
+/// on an insert of k evicting the victim v:
+///      admit k iff estimate(k) > estimate(v)
+///
+/// The sketch is a count-min sketch of 4 rows of 4-bit counters,
+/// W is 10 times its width.
+pub struct TinyLfu {
+    sketch: CountMinSketch,
+}
+
+impl TinyLfu {
+    /// Create a TinyLFU for a cache of the given capacity.
+    pub fn new(capacity: usize) -> TinyLfu {
+        let width = capacity.max(16).next_power_of_two();
+        TinyLfu {
+            sketch: CountMinSketch::new(width, 4).halve_every(10 * width as u64),
+        }
+    }
+
+    /// Returns the estimated recent frequency of a key, at most 15.
+    pub fn frequency<K: Hash>(&self, k: &K) -> u8 {
+        self.sketch.estimate(k)
+    }
+}
+
+impl<K: Hash> AdmissionPolicy<K> for TinyLfu {
+    fn record(&mut self, k: &K) {
+        self.sketch.increment(k);
+    }
+
+    fn admit(&mut self, candidate: &K, victim: &K) -> bool {
+        self.frequency(candidate) > self.frequency(victim)
+    }
+}
+
+/// Admits the keys whose insert was rejected once already.
+///
+/// Rejected keys are remembered in a Bloom filter, which is cleared once it
+/// holds capacity keys. A key inserted once, by a scan for instance, never
+/// evicts anything. Accesses are not recorded.
+pub struct SeenTwice {
+    seen: BloomFilter,
+    capacity: usize,
+}
+
+impl SeenTwice {
+    /// Create a filter remembering up to capacity keys.
+    pub fn new(capacity: usize) -> SeenTwice {
+        SeenTwice {
+            seen: BloomFilter::new(capacity, 0.01),
+            capacity: capacity.max(1),
+        }
+    }
+}
+
+impl<K: Hash> AdmissionPolicy<K> for SeenTwice {
+    fn record(&mut self, _: &K) {}
+
+    fn admit(&mut self, candidate: &K, _: &K) -> bool {
+        if self.seen.contains(candidate) {
+            return true;
+        }
+        if self.seen.len() >= self.capacity {
+            self.seen.clear();
+        }
+        self.seen.insert(candidate);
+        false
+    }
+}
@@ -0,0 +1,322 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::caching::Cache;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Wake, Waker};
+
+/// Loads the value of a key missing from an `AsyncLoadingCache`.
+/// Closures `Fn(&K) -> impl Future<Output = Result<V, E>>` are loaders.
+pub trait AsyncCacheLoader<K, V> {
+    /// Error returned when a value cannot be loaded.
+    type Error;
+    /// Future of a load.
+    type Future: Future<Output = Result<V, Self::Error>>;
+
+    /// Start loading the value of a key.
+    fn load(&self, k: &K) -> Self::Future;
+}
+
+impl<K, V, E, F, Fut> AsyncCacheLoader<K, V> for F
+where
+    F: Fn(&K) -> Fut,
+    Fut: Future<Output = Result<V, E>>,
+{
+    type Error = E;
+    type Future = Fut;
+
+    fn load(&self, k: &K) -> Fut {
+        self(k)
+    }
+}
+
+// Wakes every task waiting for a flight, so the load goes on
+// when the task that last polled it is dropped
+#[derive(Default)]
+struct Wakers {
+    wakers: Mutex<HashMap<usize, Waker>>,
+    next_id: AtomicUsize,
+}
+
+impl Wake for Wakers {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        for waker in self.wakers.lock().unwrap().values() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+struct FlightState<F: Future> {
+    // None once the load has completed or panicked
+    fut: Option<Pin<Box<F>>>,
+    output: Option<F::Output>,
+}
+
+// A load shared by the tasks missing the same key
+struct Flight<F: Future> {
+    state: Mutex<FlightState<F>>,
+    wakers: Arc<Wakers>,
+    // Updated with the cache locked
+    waiters: AtomicUsize,
+}
+
+// A joined flight and the id of the waker of the task
+type Joined<F> = (Arc<Flight<F>>, usize);
+
+struct Shared<K, C, F: Future> {
+    cache: C,
+    flights: HashMap<K, Arc<Flight<F>>>,
+}
+
+/// A read-through cache whose loader is asynchronous.
+///
+/// Concurrent `get`s missing the same key share one load, driven by
+/// whichever of them is polled. Dropping a waiting `get` is safe: the others
+/// keep driving the load, and when all of them are dropped the load is
+/// cancelled. Errors are returned to every waiter and not cached. A panic of
+/// the loader reaches the task polling it, and the other waiters start a new load.
+///
+/// Any executor can run the futures, the cache uses no runtime.
+pub struct AsyncLoadingCache<K, V, C, L: AsyncCacheLoader<K, V>> {
+    shared: Mutex<Shared<K, C, L::Future>>,
+    loader: L,
+    _marker: PhantomData<fn(V) -> V>,
+}
+
+impl<K, V, C, L> AsyncLoadingCache<K, V, C, L>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    C: Cache<K, V>,
+    L: AsyncCacheLoader<K, V>,
+    L::Error: Clone,
+{
+    /// Create an async loading cache on top of an empty cache of any policy.
+    /// With a capacity of 0, every `get` calls the loader.
+    pub fn new(cache: C, loader: L) -> AsyncLoadingCache<K, V, C, L> {
+        AsyncLoadingCache {
+            shared: Mutex::new(Shared {
+                cache,
+                flights: HashMap::new(),
+            }),
+            loader,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the loader.
+    #[inline]
+    pub fn loader(&self) -> &L {
+        &self.loader
+    }
+
+    /// Get a clone of the value with key, loading it on a miss.
+    pub fn get(&self, k: K) -> Get<'_, K, V, C, L> {
+        Get {
+            cache: self,
+            key: k,
+            flight: None,
+        }
+    }
+
+    /// Returns a clone of the cached value of a key, without loading it.
+    pub fn get_if_present(&self, k: &K) -> Option<V> {
+        self.lock().cache.get(k).cloned()
+    }
+
+    /// Insert K-V pair to the cache,
+    /// returns the entry evicted to make room for it, if any.
+    pub fn insert(&self, k: K, v: V) -> Option<(K, V)> {
+        self.lock().cache.insert(k, v)
+    }
+
+    /// Remove the value of a key, so the next `get` loads it.
+    /// A load in progress is not cancelled.
+    pub fn invalidate(&self, k: &K) -> Option<V> {
+        self.lock().cache.remove(k)
+    }
+
+    /// Returns the number of elements in the cache.
+    pub fn len(&self) -> usize {
+        self.lock().cache.len()
+    }
+
+    /// Returns true if the cache contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.lock().cache.is_empty()
+    }
+
+    /// Returns the number of loads in progress.
+    pub fn loading(&self) -> usize {
+        self.lock().flights.len()
+    }
+
+    // Returns the cached value, or joins the flight of the key
+    fn join(&self, k: &K) -> Result<V, Joined<L::Future>> {
+        let mut shared = self.lock();
+        if let Some(v) = shared.cache.get(k) {
+            return Ok(v.clone());
+        }
+        let loader = &self.loader;
+        let flight = shared.flights.entry(k.clone()).or_insert_with(|| {
+            Arc::new(Flight {
+                state: Mutex::new(FlightState {
+                    fut: Some(Box::pin(loader.load(k))),
+                    output: None,
+                }),
+                wakers: Arc::default(),
+                waiters: AtomicUsize::new(0),
+            })
+        });
+        flight.waiters.fetch_add(1, Ordering::SeqCst);
+        let id = flight.wakers.next_id.fetch_add(1, Ordering::SeqCst);
+        Err((flight.clone(), id))
+    }
+
+    // Insert the loaded value and retire the flight
+    fn complete(&self, k: &K, flight: &Arc<Flight<L::Future>>, res: &Result<V, L::Error>) {
+        let mut shared = self.lock();
+        if let Ok(v) = res {
+            shared.cache.insert(k.clone(), v.clone());
+        }
+        retire(&mut shared.flights, k, flight);
+    }
+}
+
+impl<K: Eq + Hash, V, C, L: AsyncCacheLoader<K, V>> AsyncLoadingCache<K, V, C, L> {
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, Shared<K, C, L::Future>> {
+        self.shared.lock().unwrap()
+    }
+
+    // Leave a flight, cancelling it if it was the last waiter
+    fn leave(&self, k: &K, flight: &Arc<Flight<L::Future>>, id: usize) {
+        flight.wakers.wakers.lock().unwrap().remove(&id);
+        let mut shared = self.lock();
+        if flight.waiters.fetch_sub(1, Ordering::SeqCst) == 1 {
+            retire(&mut shared.flights, k, flight);
+        }
+    }
+}
+
+// Remove the flight of a key, unless it was already replaced
+fn retire<K: Eq + Hash, F: Future>(
+    flights: &mut HashMap<K, Arc<Flight<F>>>,
+    k: &K,
+    flight: &Arc<Flight<F>>,
+) {
+    if flights.get(k).is_some_and(|f| Arc::ptr_eq(f, flight)) {
+        flights.remove(k);
+    }
+}
+
+/// Future of `AsyncLoadingCache::get`.
+pub struct Get<'a, K: Eq + Hash, V, C, L: AsyncCacheLoader<K, V>> {
+    cache: &'a AsyncLoadingCache<K, V, C, L>,
+    key: K,
+    flight: Option<Joined<L::Future>>,
+}
+
+// The loader future is pinned in its own box, nothing in Get is pinned
+impl<'a, K: Eq + Hash, V, C, L: AsyncCacheLoader<K, V>> Unpin for Get<'a, K, V, C, L> {}
+
+impl<'a, K, V, C, L> Future for Get<'a, K, V, C, L>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    C: Cache<K, V>,
+    L: AsyncCacheLoader<K, V>,
+    L::Error: Clone,
+{
+    type Output = Result<V, L::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            let (flight, id) = match &this.flight {
+                Some((flight, id)) => (flight.clone(), *id),
+                None => match this.cache.join(&this.key) {
+                    Ok(v) => return Poll::Ready(Ok(v)),
+                    Err(joined) => {
+                        this.flight = Some(joined.clone());
+                        joined
+                    }
+                },
+            };
+
+            let mut state = flight.state.lock().unwrap();
+            if let Some(res) = &state.output {
+                let res = res.clone();
+                drop(state);
+                this.leave();
+                return Poll::Ready(res);
+            }
+            let fut = match state.fut.as_mut() {
+                Some(fut) => fut,
+                None => {
+                    // The task driving the load panicked, start a new one
+                    drop(state);
+                    this.leave();
+                    continue;
+                }
+            };
+            flight
+                .wakers
+                .wakers
+                .lock()
+                .unwrap()
+                .insert(id, cx.waker().clone());
+            let waker = Waker::from(flight.wakers.clone());
+            let polled = panic::catch_unwind(AssertUnwindSafe(|| {
+                fut.as_mut().poll(&mut Context::from_waker(&waker))
+            }));
+            let res = match polled {
+                Ok(Poll::Pending) => return Poll::Pending,
+                Ok(Poll::Ready(res)) => res,
+                Err(payload) => {
+                    state.fut = None;
+                    drop(state);
+                    {
+                        let mut shared = this.cache.lock();
+                        retire(&mut shared.flights, &this.key, &flight);
+                    }
+                    waker.wake();
+                    this.leave();
+                    panic::resume_unwind(payload);
+                }
+            };
+            state.fut = None;
+            state.output = Some(res.clone());
+            drop(state);
+            this.cache.complete(&this.key, &flight, &res);
+            waker.wake();
+            this.leave();
+            return Poll::Ready(res);
+        }
+    }
+}
+
+impl<'a, K: Eq + Hash, V, C, L: AsyncCacheLoader<K, V>> Get<'a, K, V, C, L> {
+    fn leave(&mut self) {
+        if let Some((flight, id)) = self.flight.take() {
+            self.cache.leave(&self.key, &flight, id);
+        }
+    }
+}
+
+// A cancelled get leaves its flight
+impl<'a, K: Eq + Hash, V, C, L: AsyncCacheLoader<K, V>> Drop for Get<'a, K, V, C, L> {
+    fn drop(&mut self) {
+        self.leave();
+    }
+}
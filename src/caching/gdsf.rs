@@ -0,0 +1,265 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::caching::{AllPinned, Cache, Insertion};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::rc::Rc;
+
+// Priority is a totally ordered f64, so it can be used as a BTreeMap key
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Priority(f64);
+
+impl Eq for Priority {}
+
+impl PartialOrd for Priority {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Priority {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+// Entries with the same priority are evicted in insertion order
+type QueueKey = (Priority, u64);
+
+// Entry contains data and extra info for GDSF
+struct Entry<V> {
+    data: V,
+    size: u64,
+    cost: f64,
+    freq: u64,
+    key: QueueKey,
+}
+
+/// GreedyDual-Size-Frequency
+/// for each object p:
+///      H(p) = L + Fr(p) * Cost(p) / Size(p)
+/// on a hit of p:
+///      Fr(p) = Fr(p) + 1, recompute H(p)
+/// on a miss of p:
+///      Fr(p) = 1
+///      while there is not enough room for p
+///          L = min H(q) over all cached q
+///          evict the q with H(q) = L
+///      put p in the cache with H(p)
+pub struct GdsfCache<K: Eq + Hash, V> {
+    capacity: u64,
+    used: u64,
+    inflation: f64,
+    seq: u64,
+    // SAFETY: a key is one Rc held by the queue and by entries, no clone of
+    // it may leave the cache. Reprioritizing moves the same Rc to a new
+    // queue key, and evict unwraps the key after taking both out, so only
+    // the owning thread touches the count, which the Send impl relies on.
+    queue: BTreeMap<QueueKey, Rc<K>>,
+    entries: HashMap<Rc<K>, Entry<V>>,
+}
+
+// SAFETY: see queue, the keys move with the cache.
+unsafe impl<K: Eq + Hash + Send, V: Send> Send for GdsfCache<K, V> {}
+
+impl<K: Eq + Hash, V> GdsfCache<K, V> {
+    /// Create a new GDSF cache holding objects with a total size up to capacity.
+    pub fn new(capacity: u64) -> GdsfCache<K, V> {
+        GdsfCache {
+            capacity,
+            used: 0,
+            inflation: 0.0,
+            seq: 0,
+            queue: BTreeMap::new(),
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns the total size of objects the cache can hold.
+    #[inline]
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Returns the total size of cached objects.
+    #[inline]
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    /// Returns the number of elements in the cache.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if the cache contains no elements.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the current inflation value L.
+    #[inline]
+    pub fn inflation(&self) -> f64 {
+        self.inflation
+    }
+
+    /// Remove all data in the cache and reset the inflation value.
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        self.entries.clear();
+        self.used = 0;
+        self.inflation = 0.0;
+    }
+
+    /// Get value with key.
+    pub fn get(&mut self, k: &K) -> Option<&V> {
+        let inflation = self.inflation;
+        let seq = self.next_seq();
+        let e = self.entries.get_mut(k)?;
+        let key = self.queue.remove(&e.key).unwrap();
+        e.freq += 1;
+        e.key = (Self::priority(inflation, e.freq, e.cost, e.size), seq);
+        self.queue.insert(e.key, key);
+        Some(&e.data)
+    }
+
+    /// Remove an entry from the cache, returns its value.
+    /// The inflation value is unchanged.
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        let e = self.entries.remove(k)?;
+        self.queue.remove(&e.key);
+        self.used -= e.size;
+        Some(e.data)
+    }
+
+    /// Get value with key, without counting it as an access.
+    pub fn peek(&self, k: &K) -> Option<&V> {
+        self.entries.get(k).map(|e| &e.data)
+    }
+
+    /// Returns true if the key is cached, without counting it as an access.
+    pub fn contains(&self, k: &K) -> bool {
+        self.entries.contains_key(k)
+    }
+
+    /// Insert an object with its size and the cost of fetching it again,
+    /// returns the entries evicted to make room for it.
+    /// Objects larger than the whole cache are not cached.
+    pub fn insert(&mut self, k: K, v: V, size: u64, cost: f64) -> Vec<(K, V)> {
+        let size = size.max(1);
+        let freq = match self.entries.remove(&k) {
+            Some(old) => {
+                self.queue.remove(&old.key);
+                self.used -= old.size;
+                old.freq + 1
+            }
+            None => 1,
+        };
+        let mut evicted = vec![];
+        if size > self.capacity {
+            return evicted;
+        }
+        while self.used + size > self.capacity {
+            evicted.extend(self.evict());
+        }
+        let k = Rc::new(k);
+        let key = (
+            Self::priority(self.inflation, freq, cost, size),
+            self.next_seq(),
+        );
+        self.queue.insert(key, k.clone());
+        self.entries.insert(
+            k,
+            Entry {
+                data: v,
+                size,
+                cost,
+                freq,
+                key,
+            },
+        );
+        self.used += size;
+        evicted
+    }
+
+    #[inline]
+    fn priority(inflation: f64, freq: u64, cost: f64, size: u64) -> Priority {
+        Priority(inflation + freq as f64 * cost / size as f64)
+    }
+
+    #[inline]
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    fn evict(&mut self) -> Option<(K, V)> {
+        let (&key, _) = self.queue.iter().next().unwrap();
+        let (k, e) = self
+            .entries
+            .remove_entry(&self.queue.remove(&key).unwrap())
+            .unwrap();
+        self.used -= e.size;
+        self.inflation = (key.0).0;
+        Rc::try_unwrap(k).ok().map(|k| (k, e.data))
+    }
+}
+
+/// Objects inserted through `Cache` have cost 1, and size 1 unless
+/// inserted with `try_insert_sized`, which makes GDSF an LFU with aging.
+impl<K: Eq + Hash, V> Cache<K, V> for GdsfCache<K, V> {
+    fn get(&mut self, k: &K) -> Option<&V> {
+        GdsfCache::get(self, k)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        GdsfCache::peek(self, k)
+    }
+
+    fn insert(&mut self, k: K, v: V) -> Option<(K, V)> {
+        GdsfCache::insert(self, k, v, 1, 1.0).pop()
+    }
+
+    fn try_insert(&mut self, k: K, v: V) -> Result<Insertion<K, V>, AllPinned<K, V>> {
+        self.try_insert_sized(k, v, 1)
+    }
+
+    fn try_insert_sized(
+        &mut self,
+        k: K,
+        v: V,
+        size: u64,
+    ) -> Result<Insertion<K, V>, AllPinned<K, V>> {
+        if size.max(1) > self.capacity {
+            GdsfCache::remove(self, &k);
+            return Ok(Insertion::Rejected(k, v));
+        }
+        Ok(match GdsfCache::insert(self, k, v, size, 1.0).pop() {
+            Some((k, v)) => Insertion::Evicted(k, v),
+            None => Insertion::Inserted,
+        })
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        GdsfCache::remove(self, k)
+    }
+
+    fn contains(&self, k: &K) -> bool {
+        GdsfCache::contains(self, k)
+    }
+
+    fn len(&self) -> usize {
+        GdsfCache::len(self)
+    }
+
+    fn capacity(&self) -> usize {
+        GdsfCache::capacity(self) as usize
+    }
+
+    fn clear(&mut self) {
+        GdsfCache::clear(self)
+    }
+}
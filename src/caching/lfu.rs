@@ -1,5 +1,7 @@
 #![allow(dead_code)]
 #![deny(missing_docs)]
+use crate::caching::admission::AdmissionPolicy;
+use crate::caching::{AllPinned, Cache, Insertion};
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fmt::Formatter;
@@ -90,7 +92,7 @@ impl<T> Link<T> {
         }
     }
 
-    fn get_node_at<'a>(&'a self, i: usize) -> Option<NonNull<Node<T>>> {
+    fn get_node_at(&self, i: usize) -> Option<NonNull<Node<T>>> {
         let mut n = self.head;
         for _ in 0..i {
             unsafe {
@@ -100,7 +102,7 @@ impl<T> Link<T> {
         n
     }
 
-    fn get_node_rev<'a>(&'a self, i: usize) -> Option<NonNull<Node<T>>> {
+    fn get_node_rev(&self, i: usize) -> Option<NonNull<Node<T>>> {
         let mut n = self.tail;
         for _ in 0..i {
             unsafe {
@@ -175,9 +177,7 @@ impl<T> Link<T> {
         }
     }
     fn pop_front(&mut self) -> Option<NonNull<Node<T>>> {
-        if self.head.is_none() {
-            return None;
-        }
+        self.head?;
         self.len -= 1;
         let ele = self.head;
         unsafe {
@@ -198,9 +198,7 @@ impl<T> Link<T> {
     }
 
     fn pop_back(&mut self) -> Option<NonNull<Node<T>>> {
-        if self.tail.is_none() {
-            return None;
-        }
+        self.tail?;
         self.len -= 1;
         let tail = self.tail;
         unsafe {
@@ -221,12 +219,28 @@ impl<T> Link<T> {
     }
 }
 
+type NodePtr<T> = Option<NonNull<Node<T>>>;
+
 /// LfuCache O(1)
+///
+/// Pinned entries are never evicted. Their nodes leave the frequency lists
+/// until they are unpinned, while their frequencies keep counting.
+///
+/// With an admission policy, a new entry is only inserted in a full cache
+/// if the policy prefers it to the least frequently used entry.
 pub struct LfuCache<K: Eq + Hash, V> {
     capacity: usize,
+    // SAFETY: a key is one Rc shared by its node and the maps below, and no
+    // clone of it may leave the cache: for_each_entry and the admission
+    // victim only lend &K, restore builds its own Rc, and eviction gets the
+    // key back with Rc::try_unwrap. Only the thread owning the cache then
+    // touches the counts, which the Send impl relies on.
     freq_list: Option<NonNull<Link<Rc<K>>>>,
-    elements: HashMap<Rc<K>, Option<NonNull<Node<Rc<K>>>>>,
+    elements: HashMap<Rc<K>, NodePtr<Rc<K>>>,
     data: HashMap<Rc<K>, V>,
+    // Frequencies of the pinned entries
+    pinned: HashMap<Rc<K>, u64>,
+    admission: Option<Box<dyn AdmissionPolicy<K> + Send>>,
 }
 
 impl<K: Eq + Hash, V> Debug for LfuCache<K, V> {
@@ -243,6 +257,9 @@ impl<K: Eq + Hash, V> Debug for LfuCache<K, V> {
     }
 }
 
+// SAFETY: see freq_list, the keys and the nodes move with the cache.
+unsafe impl<K: Eq + Hash + Send, V: Send> Send for LfuCache<K, V> {}
+
 impl<K: Eq + Hash, V> Drop for LfuCache<K, V> {
     fn drop(&mut self) {
         self.clear();
@@ -262,22 +279,30 @@ impl<K: Eq + Hash, V> LfuCache<K, V> {
         self.data.len()
     }
 
+    /// Returns true if the cache contains no elements.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
     /// Remove all data in the cache.
     pub fn clear(&mut self) {
-        let mut cur_list = self.freq_list;
+        let mut cur_list = self.freq_list.take();
         while let Some(mut l) = cur_list {
             unsafe {
                 cur_list = l.as_mut().next;
                 while l.as_mut().pop_back().is_some() {}
+                drop(Box::from_raw(l.as_ptr()));
             }
         }
         for v in self.elements.values_mut() {
             unsafe {
-                std::ptr::drop_in_place(v.take().unwrap().as_ptr());
+                drop(Box::from_raw(v.take().unwrap().as_ptr()));
             }
         }
         self.elements.clear();
         self.data.clear();
+        self.pinned.clear();
     }
 
     /// Create a new LfuCache with give capacity
@@ -287,33 +312,139 @@ impl<K: Eq + Hash, V> LfuCache<K, V> {
             freq_list: None,
             elements: HashMap::new(),
             data: HashMap::new(),
+            pinned: HashMap::new(),
+            admission: None,
         }
     }
 
+    /// Consult an admission policy before evicting an entry for a new one.
+    /// The policy is not part of snapshots.
+    pub fn with_admission<A>(mut self, admission: A) -> LfuCache<K, V>
+    where
+        A: AdmissionPolicy<K> + Send + 'static,
+    {
+        self.admission = Some(Box::new(admission));
+        self
+    }
+
     /// Return None if k doesn't exist
     pub fn get(&mut self, k: &K) -> Option<&V> {
         if !self.data.contains_key(k) {
             return None;
         }
+        if let Some(admission) = self.admission.as_mut() {
+            admission.record(k);
+        }
         unsafe {
             self.update(k);
         }
         self.data.get(k)
     }
 
-    /// Insert a new K-V entry to the cache
-    pub fn insert(&mut self, k: K, v: V) {
-        let k = Rc::new(k);
+    /// Return None if k doesn't exist, without counting it as an access
+    pub fn peek(&self, k: &K) -> Option<&V> {
+        self.data.get(k)
+    }
+
+    /// Returns true if k exists, without counting it as an access
+    pub fn contains(&self, k: &K) -> bool {
+        self.data.contains_key(k)
+    }
+
+    /// Remove an entry, returns its value
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        let node = self.elements.remove(k)?;
+        unsafe {
+            if self.pinned.remove(k).is_none() {
+                self.detach(node);
+            }
+            drop(Box::from_raw(node.unwrap().as_ptr()));
+        }
+        self.data.remove(k)
+    }
+
+    /// Pin an entry so that it is never evicted, returns false if k doesn't exist
+    pub fn pin(&mut self, k: &K) -> bool {
+        let &node = match self.elements.get(k) {
+            Some(node) => node,
+            None => return false,
+        };
+        if self.pinned.contains_key(k) {
+            return true;
+        }
+        unsafe {
+            let times = node.unwrap().as_ref().list.unwrap().as_ref().times;
+            self.detach(node);
+            self.pinned
+                .insert(node.unwrap().as_ref().value.clone(), times);
+        }
+        true
+    }
+
+    /// Unpin an entry, it becomes the most recent entry of its frequency.
+    /// Returns false if k isn't pinned
+    pub fn unpin(&mut self, k: &K) -> bool {
+        let times = match self.pinned.remove(k) {
+            Some(times) => times,
+            None => return false,
+        };
+        unsafe {
+            let list = self.list_of(times);
+            (*list.as_ptr()).push_front_node(self.elements[k]);
+        }
+        true
+    }
+
+    /// Returns true if k is pinned
+    pub fn is_pinned(&self, k: &K) -> bool {
+        self.pinned.contains_key(k)
+    }
+
+    /// Returns the number of pinned entries
+    pub fn pinned_len(&self) -> usize {
+        self.pinned.len()
+    }
+
+    /// Insert a new K-V entry to the cache,
+    /// returns the least frequently used entry if it was evicted.
+    /// A new entry the admission policy rejects, or that finds the cache
+    /// full of pinned entries, is dropped. Use `try_insert` to get it back
+    /// when entries are pinned.
+    pub fn insert(&mut self, k: K, v: V) -> Option<(K, V)> {
+        self.try_insert(k, v).ok().and_then(Insertion::evicted)
+    }
+
+    /// Insert a new K-V entry to the cache,
+    /// returns the least frequently used entry if it was evicted,
+    /// or the new entry if it was rejected,
+    /// or an error if the cache is full and every entry is pinned
+    pub fn try_insert(&mut self, k: K, v: V) -> Result<Insertion<K, V>, AllPinned<K, V>> {
+        if self.capacity == 0 {
+            return Ok(Insertion::Rejected(k, v));
+        }
+        if let Some(admission) = self.admission.as_mut() {
+            admission.record(&k);
+        }
         if self.elements.contains_key(&k) {
-            self.data.insert(k.clone(), v);
             unsafe {
                 self.update(&k);
             }
-            return;
+            self.data.insert(Rc::new(k), v);
+            return Ok(Insertion::Inserted);
         };
-        unsafe {
-            self.eviction();
+        if self.data.len() >= self.capacity {
+            let victim = match self.freq_list {
+                Some(list) => unsafe { &*list.as_ref().tail.unwrap().as_ref().value },
+                None => return Err(AllPinned(k, v)),
+            };
+            if let Some(admission) = self.admission.as_mut() {
+                if !admission.admit(&k, victim) {
+                    return Ok(Insertion::Rejected(k, v));
+                }
+            }
         }
+        let k = Rc::new(k);
+        let evicted = unsafe { self.eviction() };
         let n = NonNull::new(to_raw(Node {
             prev: None,
             next: None,
@@ -334,11 +465,57 @@ impl<K: Eq + Hash, V> LfuCache<K, V> {
             }
             self.freq_list.unwrap().as_mut().push_front_node(n);
         }
+        Ok(match evicted {
+            Some((k, v)) => Insertion::Evicted(k, v),
+            None => Insertion::Inserted,
+        })
+    }
+}
+
+impl<K: Eq + Hash, V> Cache<K, V> for LfuCache<K, V> {
+    fn get(&mut self, k: &K) -> Option<&V> {
+        LfuCache::get(self, k)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        LfuCache::peek(self, k)
+    }
+
+    fn insert(&mut self, k: K, v: V) -> Option<(K, V)> {
+        LfuCache::insert(self, k, v)
+    }
+
+    fn try_insert(&mut self, k: K, v: V) -> Result<Insertion<K, V>, AllPinned<K, V>> {
+        LfuCache::try_insert(self, k, v)
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        LfuCache::remove(self, k)
+    }
+
+    fn contains(&self, k: &K) -> bool {
+        LfuCache::contains(self, k)
+    }
+
+    fn len(&self) -> usize {
+        LfuCache::len(self)
+    }
+
+    fn capacity(&self) -> usize {
+        LfuCache::capacity(self)
+    }
+
+    fn clear(&mut self) {
+        LfuCache::clear(self)
     }
 }
 
 impl<K: Eq + Hash, V> LfuCache<K, V> {
     unsafe fn update(&mut self, k: &K) {
+        if let Some(times) = self.pinned.get_mut(k) {
+            *times += 1;
+            return;
+        }
         let &node = self.elements.get(k).unwrap();
         let mut cur_list = node.unwrap().as_ref().list.unwrap();
         // Remove node from original list
@@ -374,6 +551,7 @@ impl<K: Eq + Hash, V> LfuCache<K, V> {
             if old_list == self.freq_list.unwrap() {
                 // Remove empty head list
                 self.freq_list = old_list.as_ref().next;
+                self.freq_list.unwrap().as_mut().prev = None;
             } else if old_list.as_ref().next.is_none() {
                 // Unreachable
                 unreachable!();
@@ -381,23 +559,175 @@ impl<K: Eq + Hash, V> LfuCache<K, V> {
                 old_list.as_ref().prev.unwrap().as_mut().next = old_list.as_ref().next;
                 old_list.as_ref().next.unwrap().as_mut().prev = old_list.as_ref().prev;
             }
-            std::ptr::drop_in_place(old_list.as_ptr());
-        }
-    }
-    unsafe fn eviction(&mut self) {
-        if self.data.len() == self.capacity {
-            let ptr = self.freq_list.unwrap().as_mut().pop_back();
-            let k = ptr.unwrap().as_ref().value.clone();
-            self.elements.remove(&k);
-            self.data.remove(&k);
-            std::ptr::drop_in_place(ptr.unwrap().as_ptr());
-
-            // Remove empty list
-            if self.freq_list.unwrap().as_ref().len == 0 {
-                let empty_head = self.freq_list;
-                self.freq_list = self.freq_list.unwrap().as_ref().next;
-                std::ptr::drop_in_place(empty_head.unwrap().as_ptr());
+            drop(Box::from_raw(old_list.as_ptr()));
+        }
+    }
+    unsafe fn eviction(&mut self) -> Option<(K, V)> {
+        if self.data.len() < self.capacity {
+            return None;
+        }
+        let ptr = self.freq_list.unwrap().as_mut().pop_back();
+        let k = ptr.unwrap().as_ref().value.clone();
+        self.elements.remove(&k);
+        let v = self.data.remove(&k).unwrap();
+        drop(Box::from_raw(ptr.unwrap().as_ptr()));
+
+        // Remove empty list
+        if self.freq_list.unwrap().as_ref().len == 0 {
+            let empty_head = self.freq_list;
+            self.freq_list = self.freq_list.unwrap().as_ref().next;
+            if let Some(mut h) = self.freq_list {
+                h.as_mut().prev = None;
+            }
+            drop(Box::from_raw(empty_head.unwrap().as_ptr()));
+        }
+        Rc::try_unwrap(k).ok().map(|k| (k, v))
+    }
+}
+
+impl<K: Eq + Hash, V> LfuCache<K, V> {
+    // Visit every entry with its frequency, in eviction order, then the pinned ones
+    pub(crate) fn for_each_entry<'a, F: FnMut(&'a K, &'a V, u64)>(&'a self, mut f: F) {
+        let mut cur_list = self.freq_list;
+        while let Some(l) = cur_list {
+            unsafe {
+                let times = l.as_ref().times;
+                let mut cur = l.as_ref().tail;
+                while let Some(n) = cur {
+                    let k = &n.as_ref().value;
+                    f(k, &self.data[k], times);
+                    cur = n.as_ref().prev;
+                }
+                cur_list = l.as_ref().next;
+            }
+        }
+        for (k, &times) in self.pinned.iter() {
+            f(k, &self.data[k], times);
+        }
+    }
+
+    // Remove a node from its list, and the list if it becomes empty
+    unsafe fn detach(&mut self, node: NodePtr<Rc<K>>) {
+        let mut list = node.unwrap().as_ref().list.unwrap();
+        list.as_mut().remove_node(node);
+        if list.as_ref().len == 0 {
+            let (prev, next) = (list.as_ref().prev, list.as_ref().next);
+            match prev {
+                Some(mut p) => p.as_mut().next = next,
+                None => self.freq_list = next,
+            }
+            if let Some(mut n) = next {
+                n.as_mut().prev = prev;
+            }
+            drop(Box::from_raw(list.as_ptr()));
+        }
+    }
+
+    // Returns the list of a frequency, creating it if needed
+    unsafe fn list_of(&mut self, times: u64) -> NonNull<Link<Rc<K>>> {
+        // Find the last list with at most `times`
+        let mut prev: Option<NonNull<Link<Rc<K>>>> = None;
+        let mut cur = self.freq_list;
+        while let Some(c) = cur {
+            if c.as_ref().times > times {
+                break;
+            }
+            prev = cur;
+            cur = c.as_ref().next;
+        }
+        match prev {
+            Some(p) if p.as_ref().times == times => p,
+            _ => {
+                let list = NonNull::new(to_raw(Link::new(times))).unwrap();
+                (*list.as_ptr()).prev = prev;
+                (*list.as_ptr()).next = cur;
+                match prev {
+                    Some(mut p) => p.as_mut().next = Some(list),
+                    None => self.freq_list = Some(list),
+                }
+                if let Some(mut c) = cur {
+                    c.as_mut().prev = Some(list);
+                }
+                list
+            }
+        }
+    }
+
+    // Add an entry with its frequency, as the most recent one of that frequency.
+    // Entries must be restored in eviction order and the cache must not be full.
+    pub(crate) fn restore(&mut self, k: K, v: V, times: u64) {
+        let k = Rc::new(k);
+        if self.data.len() >= self.capacity || self.data.contains_key(&k) {
+            return;
+        }
+        let times = times.max(1);
+        unsafe {
+            let list = self.list_of(times);
+            let n = NonNull::new(to_raw(Node {
+                prev: None,
+                next: None,
+                list: None,
+                value: k.clone(),
+            }));
+            (*list.as_ptr()).push_front_node(n);
+            self.elements.insert(k.clone(), n);
+        }
+        self.data.insert(k, v);
+    }
+
+    // True if the admission policy holds state a snapshot does not
+    pub(crate) fn has_learned_state(&self) -> bool {
+        self.admission.is_some()
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use super::LfuCache;
+    use serde::de::{Deserialize, Deserializer};
+    use serde::ser::{Serialize, Serializer};
+    use std::hash::Hash;
+
+    #[derive(serde::Serialize)]
+    #[serde(rename = "LfuCache")]
+    struct StateRef<'a, K, V> {
+        capacity: usize,
+        entries: Vec<(&'a K, &'a V, u64)>,
+    }
+
+    #[derive(serde::Deserialize)]
+    #[serde(rename = "LfuCache")]
+    struct State<K, V> {
+        capacity: usize,
+        entries: Vec<(K, V, u64)>,
+    }
+
+    /// Entries are serialized with their frequency, in eviction order.
+    /// Pinned entries come last and are deserialized unpinned.
+    impl<K: Eq + Hash + Serialize, V: Serialize> Serialize for LfuCache<K, V> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut entries = Vec::with_capacity(self.len());
+            self.for_each_entry(|k, v, times| entries.push((k, v, times)));
+            StateRef {
+                capacity: self.capacity,
+                entries,
+            }
+            .serialize(serializer)
+        }
+    }
+
+    impl<'de, K, V> Deserialize<'de> for LfuCache<K, V>
+    where
+        K: Eq + Hash + Deserialize<'de>,
+        V: Deserialize<'de>,
+    {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let state = State::<K, V>::deserialize(deserializer)?;
+            let mut cache = LfuCache::new(state.capacity);
+            for (k, v, times) in state.entries {
+                cache.restore(k, v, times);
             }
+            Ok(cache)
         }
     }
 }
@@ -0,0 +1,318 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::caching::two_q_lru::SimplifiedTwoQ;
+use crate::caching::{Cache, Loaded};
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Loads the value of a key missing from a `LoadingCache`.
+/// Closures `Fn(&K) -> Result<V, E>` are loaders.
+pub trait CacheLoader<K, V> {
+    /// Error returned when a value cannot be loaded.
+    type Error;
+
+    /// Load the value of a key.
+    fn load(&self, k: &K) -> Result<V, Self::Error>;
+}
+
+impl<K, V, E, F> CacheLoader<K, V> for F
+where
+    F: Fn(&K) -> Result<V, E>,
+{
+    type Error = E;
+
+    fn load(&self, k: &K) -> Result<V, E> {
+        self(k)
+    }
+}
+
+// Recent load errors, bounded by a 2Q and expiring after ttl
+struct ErrorCache<K: Eq + Hash, E> {
+    errors: SimplifiedTwoQ<K, (E, Instant)>,
+    ttl: Duration,
+    // E::clone, so only caching errors requires E: Clone
+    clone: fn(&E) -> E,
+}
+
+// Reloads running at once by default
+const RELOAD_THREADS: usize = 4;
+
+// A reload finished in the background, with the write time of the entry it refreshes
+type Reloaded<K, V, E> = (K, Instant, Result<V, E>);
+
+// Reloads of the entries older than interval, run on other threads
+struct Refresh<K, V, E> {
+    interval: Duration,
+    spawn: Box<dyn Fn(K, Instant)>,
+    done: Receiver<Reloaded<K, V, E>>,
+    // Keys being reloaded
+    pending: HashSet<K>,
+}
+
+/// A read-through cache: a miss is loaded with the loader and inserted.
+/// Load errors are returned to the caller and not cached, unless
+/// `cache_errors` is set, in which case the error of a key is returned
+/// again without calling the loader until it expires.
+///
+/// With `expire_after_write`, entries older than the ttl are misses.
+/// With `refresh_after_write`, entries older than the interval are still
+/// returned, but reloaded on another thread. The reloaded value replaces the
+/// entry on a later call, unless the entry was written in the meantime, and
+/// a failed reload keeps the old value until the next refresh. At most
+/// `reload_threads` reloads run at once, a stale entry found while they all
+/// run is reloaded on a later call.
+///
+/// Entries the cache held before it was wrapped count as written when they
+/// are first read, so a warm cache can be restored and then wrapped.
+///
+/// A loaded value the cache does not take, because its admission policy
+/// rejects it, is returned as `Loaded::Uncached`, and loaded again on the
+/// next `get`.
+pub struct LoadingCache<K: Eq + Hash, V, C, L: CacheLoader<K, V>> {
+    cache: C,
+    loader: Arc<L>,
+    errors: Option<ErrorCache<K, L::Error>>,
+    // Write times, only kept when entries expire or are refreshed
+    written: Option<HashMap<K, Instant>>,
+    expire: Option<Duration>,
+    refresh: Option<Refresh<K, V, L::Error>>,
+    // Most reloads running at once
+    reload_threads: usize,
+}
+
+impl<K, V, C, L> LoadingCache<K, V, C, L>
+where
+    K: Eq + Hash + Clone,
+    C: Cache<K, V>,
+    L: CacheLoader<K, V>,
+{
+    /// Create a loading cache on top of a cache of any policy.
+    /// With a capacity of 0, every `get` calls the loader.
+    pub fn new(cache: C, loader: L) -> LoadingCache<K, V, C, L> {
+        LoadingCache {
+            cache,
+            loader: Arc::new(loader),
+            errors: None,
+            written: None,
+            expire: None,
+            refresh: None,
+            reload_threads: RELOAD_THREADS,
+        }
+    }
+
+    /// Remember the errors of up to capacity keys for ttl.
+    pub fn cache_errors(mut self, capacity: usize, ttl: Duration) -> LoadingCache<K, V, C, L>
+    where
+        L::Error: Clone,
+    {
+        self.errors = Some(ErrorCache {
+            errors: SimplifiedTwoQ::with_capacity(capacity),
+            ttl,
+            clone: L::Error::clone,
+        });
+        self
+    }
+
+    /// Treat the entries written more than ttl ago as misses.
+    pub fn expire_after_write(mut self, ttl: Duration) -> LoadingCache<K, V, C, L> {
+        self.expire = Some(ttl);
+        self.written.get_or_insert_with(HashMap::new);
+        self
+    }
+
+    /// Reload in the background the entries written more than interval ago.
+    pub fn refresh_after_write(mut self, interval: Duration) -> LoadingCache<K, V, C, L>
+    where
+        K: Send + 'static,
+        V: Send + 'static,
+        L: Send + Sync + 'static,
+        L::Error: Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let loader = self.loader.clone();
+        let spawn = move |k: K, written: Instant| {
+            let (loader, tx) = (loader.clone(), tx.clone());
+            thread::spawn(move || {
+                let res = loader.load(&k);
+                // The cache may be gone
+                let _ = tx.send((k, written, res));
+            });
+        };
+        self.refresh = Some(Refresh {
+            interval,
+            spawn: Box::new(spawn),
+            done: rx,
+            pending: HashSet::new(),
+        });
+        self.written.get_or_insert_with(HashMap::new);
+        self
+    }
+
+    /// Run at most n reloads at once, 4 by default.
+    /// Only matters with `refresh_after_write`.
+    pub fn reload_threads(mut self, n: usize) -> LoadingCache<K, V, C, L> {
+        assert!(n > 0, "LoadingCache needs a reload thread");
+        self.reload_threads = n;
+        self
+    }
+
+    /// Returns the wrapped cache.
+    #[inline]
+    pub fn cache(&self) -> &C {
+        &self.cache
+    }
+
+    /// Returns the loader.
+    #[inline]
+    pub fn loader(&self) -> &L {
+        &self.loader
+    }
+
+    /// Get value with key, loading it on a miss.
+    pub fn get(&mut self, k: &K) -> Result<Loaded<'_, V>, L::Error> {
+        if self.present(k) {
+            self.refresh_if_stale(k);
+            return Ok(Loaded::Cached(self.cache.get(k).unwrap()));
+        }
+        if let Some(e) = self.cached_error(k) {
+            return Err(e);
+        }
+        match self.loader.load(k) {
+            Ok(v) => Ok(self.write(k.clone(), v).0),
+            Err(e) => {
+                if let Some(cache) = self.errors.as_mut() {
+                    let expire = Instant::now() + cache.ttl;
+                    cache.errors.insert(k.clone(), ((cache.clone)(&e), expire));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Returns the error of the key if it has not expired
+    fn cached_error(&mut self, k: &K) -> Option<L::Error> {
+        let cache = self.errors.as_mut()?;
+        let (e, expire) = cache.errors.get(k)?;
+        if *expire > Instant::now() {
+            return Some((cache.clone)(e));
+        }
+        cache.errors.remove(k);
+        None
+    }
+
+    // Apply the finished reloads, then returns true if the key
+    // is cached and not expired. An expired entry is removed.
+    fn present(&mut self, k: &K) -> bool {
+        self.apply_reloads();
+        if !self.cache.contains(k) {
+            return false;
+        }
+        let ttl = match self.expire {
+            Some(ttl) => ttl,
+            None => return true,
+        };
+        let written = self.written(k).unwrap();
+        if written.elapsed() < ttl {
+            return true;
+        }
+        self.cache.remove(k);
+        self.written.as_mut().unwrap().remove(k);
+        false
+    }
+
+    fn refresh_if_stale(&mut self, k: &K) {
+        if self.refresh.is_none() {
+            return;
+        }
+        let written = self.written(k).unwrap();
+        let refresh = self.refresh.as_mut().unwrap();
+        if written.elapsed() >= refresh.interval
+            && refresh.pending.len() < self.reload_threads
+            && refresh.pending.insert(k.clone())
+        {
+            (refresh.spawn)(k.clone(), written);
+        }
+    }
+
+    // Returns the write time of a cached key, if write times are kept.
+    // An entry cached before the cache was wrapped is written now.
+    fn written(&mut self, k: &K) -> Option<Instant> {
+        let written = self.written.as_mut()?;
+        Some(*written.entry(k.clone()).or_insert_with(Instant::now))
+    }
+
+    fn apply_reloads(&mut self) {
+        let refresh = match self.refresh.as_mut() {
+            Some(refresh) => refresh,
+            None => return,
+        };
+        let done: Vec<_> = refresh.done.try_iter().collect();
+        for (k, at, res) in done {
+            self.refresh.as_mut().unwrap().pending.remove(&k);
+            let unchanged = self.written.as_ref().unwrap().get(&k) == Some(&at);
+            if let (Ok(v), true) = (res, unchanged) {
+                self.write(k, v);
+            }
+        }
+    }
+
+    // Insert an entry and track its write time,
+    // returns its value and the evicted entry
+    fn write(&mut self, k: K, v: V) -> (Loaded<'_, V>, Option<(K, V)>) {
+        let (v, evicted) = self.cache.insert_loaded(k.clone(), v);
+        if let Some(written) = self.written.as_mut() {
+            match v {
+                Loaded::Cached(_) => written.insert(k, Instant::now()),
+                Loaded::Uncached(_) => written.remove(&k),
+            };
+            if let Some((k, _)) = &evicted {
+                written.remove(k);
+            }
+        }
+        (v, evicted)
+    }
+
+    /// Returns the cached value of a key, without loading it.
+    pub fn get_if_present(&mut self, k: &K) -> Option<&V> {
+        if self.present(k) {
+            self.cache.get(k)
+        } else {
+            None
+        }
+    }
+
+    /// Insert K-V pair to the cache, replacing the error of the key,
+    /// returns the entry evicted to make room for it, if any.
+    pub fn insert(&mut self, k: K, v: V) -> Option<(K, V)> {
+        if let Some(cache) = self.errors.as_mut() {
+            cache.errors.remove(&k);
+        }
+        self.write(k, v).1
+    }
+
+    /// Remove the value and the error of a key, so the next `get` loads it.
+    pub fn invalidate(&mut self, k: &K) -> Option<V> {
+        if let Some(cache) = self.errors.as_mut() {
+            cache.errors.remove(k);
+        }
+        if let Some(written) = self.written.as_mut() {
+            written.remove(k);
+        }
+        self.cache.remove(k)
+    }
+
+    /// Remove all values and errors.
+    pub fn invalidate_all(&mut self) {
+        if let Some(cache) = self.errors.as_mut() {
+            cache.errors.clear();
+        }
+        if let Some(written) = self.written.as_mut() {
+            written.clear();
+        }
+        self.cache.clear();
+    }
+}
@@ -0,0 +1,340 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::caching::{AllPinned, Cache, Insertion};
+use crate::common::list::{List, NodePtr};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+// Entry contains data and extra info for MQ
+struct Entry<K, V> {
+    queue: usize,
+    freq: u64,
+    expire: u64,
+    node: NodePtr<Rc<K>>,
+    data: V,
+}
+
+// Ghost remembers the access frequency of an evicted key in Qout
+struct Ghost {
+    freq: u64,
+    node: NodePtr<u64>,
+}
+
+#[inline]
+fn fingerprint<K: Hash>(k: &K) -> u64 {
+    let mut h = DefaultHasher::new();
+    k.hash(&mut h);
+    h.finish()
+}
+
+#[inline]
+unsafe fn free_node<T>(node: NodePtr<T>) {
+    drop(Box::from_raw(node.unwrap().as_ptr()));
+}
+
+/// Multi-Queue (MQ)
+/// Access x:
+/// if x is in cache
+/// then
+///      find x in some LRU queue Qi
+///      remove x from Qi
+/// else /* cache miss */
+///      if no free space, EvictBlock()
+///      if x is in Qout
+///      then
+///          remove x from Qout, x.freq = remembered freq
+///      else
+///          x.freq = 0
+///      end if
+/// end if
+/// x.freq++
+/// put x on the tail of Qk, k = min(log2(x.freq), m - 1)
+/// x.expireTime = currentTime + lifeTime
+/// Adjust()
+///
+/// EvictBlock():
+///      victim = head of the first non-empty queue
+///      remove victim, put its identity and freq to Qout
+///
+/// Adjust():
+///      currentTime++
+///      for k = 1..m-1
+///          if head of Qk expired, move it to the tail of Qk-1
+pub struct MultiQueue<K: Eq + Hash, V> {
+    // SAFETY: a key is one Rc shared by its node in a queue and its entry,
+    // no clone of it may leave the cache. Queue moves relink the same node,
+    // and evict unwraps the key once the node is gone, so only the owning
+    // thread touches the counts, which the Send impl relies on.
+    queues: Vec<List<Rc<K>>>,
+    entries: HashMap<Rc<K>, Entry<K, V>>,
+    // Qout only keeps fingerprints of the evicted keys
+    qout: List<u64>,
+    history: HashMap<u64, Ghost>,
+    qout_cap: usize,
+    life_time: u64,
+    current_time: u64,
+    cap: usize,
+}
+
+// SAFETY: see queues, the keys and the nodes move with the cache.
+unsafe impl<K: Eq + Hash + Send, V: Send> Send for MultiQueue<K, V> {}
+
+impl<K: Eq + Hash, V> Drop for MultiQueue<K, V> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<K: Eq + Hash, V> MultiQueue<K, V> {
+    /// Create a new MQ with capacity, 8 queues, a Qout of 4 * capacity
+    /// and a life time of 4 * capacity accesses.
+    pub fn with_capacity(cap: usize) -> MultiQueue<K, V> {
+        Self::with_config(cap, 8, cap * 4, cap as u64 * 4)
+    }
+
+    /// Create a new MQ with capacity, number of queues (m), Qout size and
+    /// life time in accesses.
+    pub fn with_config(
+        cap: usize,
+        queues: usize,
+        qout_cap: usize,
+        life_time: u64,
+    ) -> MultiQueue<K, V> {
+        assert!(queues > 0, "MQ needs at least one queue");
+        MultiQueue {
+            queues: (0..queues).map(|_| List::default()).collect(),
+            entries: HashMap::new(),
+            qout: List::default(),
+            history: HashMap::new(),
+            qout_cap,
+            life_time,
+            current_time: 0,
+            cap,
+        }
+    }
+
+    /// Returns the number of elements the cache can hold.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// Returns the number of elements in the cache.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if the cache contains no elements.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Remove all data in the cache, including the Qout history.
+    pub fn clear(&mut self) {
+        for q in self.queues.iter_mut() {
+            while let Some(p) = q.pop_back() {
+                unsafe { free_node(Some(p)) };
+            }
+        }
+        while let Some(p) = self.qout.pop_back() {
+            unsafe { free_node(Some(p)) };
+        }
+        self.entries.clear();
+        self.history.clear();
+        self.current_time = 0;
+    }
+
+    /// Remove an entry from the cache, returns its value.
+    /// The key is not remembered in Qout.
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        let e = self.entries.remove(k)?;
+        unsafe {
+            self.queues[e.queue].remove_node(e.node);
+            free_node(e.node);
+        }
+        Some(e.data)
+    }
+
+    /// Get value with key, without counting it as an access.
+    pub fn peek(&self, k: &K) -> Option<&V> {
+        self.entries.get(k).map(|e| &e.data)
+    }
+
+    /// Returns true if the key is cached, without counting it as an access.
+    pub fn contains(&self, k: &K) -> bool {
+        self.entries.contains_key(k)
+    }
+
+    /// Get value with key.
+    pub fn get(&mut self, k: &K) -> Option<&V> {
+        if !self.entries.contains_key(k) {
+            return None;
+        }
+        self.access(k);
+        self.entries.get(k).map(|e| &e.data)
+    }
+
+    /// Insert K-V pair to the cache,
+    /// returns the entry evicted to make room for it, if any.
+    pub fn insert(&mut self, k: K, v: V) -> Option<(K, V)> {
+        if let Some(entry) = self.entries.get_mut(&k) {
+            entry.data = v;
+            self.access(&k);
+            return None;
+        }
+        if self.cap == 0 {
+            return None;
+        }
+        let evicted = if self.entries.len() >= self.cap {
+            self.evict()
+        } else {
+            None
+        };
+        let freq = match self.history.remove(&fingerprint(&k)) {
+            Some(ghost) => unsafe {
+                self.qout.remove_node(ghost.node);
+                free_node(ghost.node);
+                ghost.freq
+            },
+            None => 0,
+        } + 1;
+        let k = Rc::new(k);
+        let queue = self.queue_of(freq);
+        self.queues[queue].push_front(k.clone());
+        self.entries.insert(
+            k.clone(),
+            Entry {
+                queue,
+                freq,
+                expire: self.current_time + self.life_time,
+                node: self.queues[queue].head,
+                data: v,
+            },
+        );
+        self.adjust();
+        evicted
+    }
+
+    #[inline]
+    fn queue_of(&self, freq: u64) -> usize {
+        let log2 = 63 - freq.max(1).leading_zeros() as usize;
+        log2.min(self.queues.len() - 1)
+    }
+
+    fn access(&mut self, k: &K) {
+        let queue_cnt = self.queues.len();
+        let expire = self.current_time + self.life_time;
+        let e = self.entries.get_mut(k).unwrap();
+        e.freq += 1;
+        let log2 = 63 - e.freq.leading_zeros() as usize;
+        let queue = log2.min(queue_cnt - 1);
+        unsafe {
+            self.queues[e.queue].remove_node(e.node);
+            self.queues[queue].push_front_node(e.node);
+        }
+        e.queue = queue;
+        e.expire = expire;
+        self.adjust();
+    }
+
+    fn adjust(&mut self) {
+        self.current_time += 1;
+        for k in 1..self.queues.len() {
+            let tail = match self.queues[k].tail {
+                Some(t) => t,
+                None => continue,
+            };
+            let e = unsafe { self.entries.get_mut(&tail.as_ref().value).unwrap() };
+            if e.expire >= self.current_time {
+                continue;
+            }
+            unsafe {
+                self.queues[k].remove_node(e.node);
+                self.queues[k - 1].push_front_node(e.node);
+            }
+            e.queue = k - 1;
+            e.expire = self.current_time + self.life_time;
+        }
+    }
+
+    fn evict(&mut self) -> Option<(K, V)> {
+        let p = self.queues.iter_mut().find(|q| q.len > 0)?.pop_back();
+        let node = unsafe { Box::from_raw(p.unwrap().as_ptr()) };
+        let (k, e) = self.entries.remove_entry(&node.value).unwrap();
+        drop(node);
+        if self.qout_cap > 0 {
+            let fp = fingerprint(&*k);
+            // A colliding key replaces the ghost of the fingerprint
+            if let Some(ghost) = self.history.remove(&fp) {
+                unsafe {
+                    self.qout.remove_node(ghost.node);
+                    free_node(ghost.node);
+                }
+            }
+            if self.history.len() >= self.qout_cap {
+                let oldest = self.qout.pop_back();
+                unsafe {
+                    self.history.remove(&oldest.unwrap().as_ref().value);
+                    free_node(oldest);
+                }
+            }
+            self.qout.push_front(fp);
+            self.history.insert(
+                fp,
+                Ghost {
+                    freq: e.freq,
+                    node: self.qout.head,
+                },
+            );
+        }
+        Rc::try_unwrap(k).ok().map(|k| (k, e.data))
+    }
+}
+
+impl<K: Eq + Hash, V> Cache<K, V> for MultiQueue<K, V> {
+    fn get(&mut self, k: &K) -> Option<&V> {
+        MultiQueue::get(self, k)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        MultiQueue::peek(self, k)
+    }
+
+    fn insert(&mut self, k: K, v: V) -> Option<(K, V)> {
+        MultiQueue::insert(self, k, v)
+    }
+
+    fn try_insert(&mut self, k: K, v: V) -> Result<Insertion<K, V>, AllPinned<K, V>> {
+        if self.cap == 0 {
+            return Ok(Insertion::Rejected(k, v));
+        }
+        Ok(match MultiQueue::insert(self, k, v) {
+            Some((k, v)) => Insertion::Evicted(k, v),
+            None => Insertion::Inserted,
+        })
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        MultiQueue::remove(self, k)
+    }
+
+    fn contains(&self, k: &K) -> bool {
+        MultiQueue::contains(self, k)
+    }
+
+    fn len(&self) -> usize {
+        MultiQueue::len(self)
+    }
+
+    fn capacity(&self) -> usize {
+        MultiQueue::capacity(self)
+    }
+
+    fn clear(&mut self) {
+        MultiQueue::clear(self)
+    }
+}
@@ -0,0 +1,158 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::common::fenwick::Fenwick;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Computes LRU stack distances in O(log n) per access.
+/// Every key marks the time slot of its last access in a Fenwick tree,
+/// so the number of distinct keys accessed after a slot is a range sum.
+/// Slots are renumbered when the tree is full, so memory is bounded by
+/// the number of distinct keys rather than the length of the trace.
+pub struct StackDistance<K> {
+    last: HashMap<K, usize>,
+    marks: Fenwick,
+    now: usize,
+}
+
+impl<K: Eq + Hash> Default for StackDistance<K> {
+    fn default() -> Self {
+        StackDistance {
+            last: HashMap::new(),
+            marks: Fenwick::new(64),
+            now: 0,
+        }
+    }
+}
+
+impl<K: Eq + Hash> StackDistance<K> {
+    /// Create an empty stack.
+    pub fn new() -> StackDistance<K> {
+        Self::default()
+    }
+
+    /// Returns the number of distinct keys in the stack.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.last.len()
+    }
+
+    /// Returns true if no key was accessed.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.last.is_empty()
+    }
+
+    /// Access a key, returns the number of distinct keys accessed since
+    /// its previous access, or None if it is accessed for the first time.
+    /// An LRU cache of size c hits iff the distance is less than c.
+    pub fn access(&mut self, k: K) -> Option<usize> {
+        if self.now == self.marks.len() {
+            self.compact();
+        }
+        let now = self.now;
+        self.now += 1;
+        self.marks.add(now, 1);
+        let prev = self.last.insert(k, now)?;
+        self.marks.add(prev, -1);
+        Some(self.marks.range(prev + 1, now) as usize)
+    }
+
+    /// Remove a key from the stack, as if it was never accessed.
+    pub fn forget(&mut self, k: &K) {
+        if let Some(prev) = self.last.remove(k) {
+            self.marks.add(prev, -1);
+        }
+    }
+
+    // Renumber the live slots to 0..len and grow the tree if needed
+    fn compact(&mut self) {
+        let mut slots: Vec<&mut usize> = self.last.values_mut().collect();
+        slots.sort_unstable_by_key(|s| **s);
+        let len = slots.len();
+        for (i, s) in slots.into_iter().enumerate() {
+            *s = i;
+        }
+        self.marks = Fenwick::new((len * 2).max(64));
+        for i in 0..len {
+            self.marks.add(i, 1);
+        }
+        self.now = len;
+    }
+}
+
+/// Miss ratio of an LRU cache for every cache size.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MissRatioCurve {
+    // hist[d] is the weight of the accesses with stack distance d
+    hist: Vec<f64>,
+    cold: f64,
+    total: f64,
+}
+
+impl MissRatioCurve {
+    /// Build the exact curve of a trace in one pass (Mattson's stack algorithm).
+    pub fn from_trace<K, I>(trace: I) -> MissRatioCurve
+    where
+        K: Eq + Hash,
+        I: IntoIterator<Item = K>,
+    {
+        let mut stack = StackDistance::new();
+        let mut mrc = MissRatioCurve::default();
+        for k in trace {
+            mrc.record(stack.access(k), 1.0);
+        }
+        mrc
+    }
+
+    // Add an access with its stack distance, None for a cold miss
+    pub(crate) fn record(&mut self, distance: Option<usize>, weight: f64) {
+        self.total += weight;
+        match distance {
+            Some(d) => {
+                if self.hist.len() <= d {
+                    self.hist.resize(d + 1, 0.0);
+                }
+                self.hist[d] += weight;
+            }
+            None => self.cold += weight,
+        }
+    }
+
+    // Multiply all recorded weights by factor
+    pub(crate) fn scale(&mut self, factor: f64) {
+        self.hist.iter_mut().for_each(|w| *w *= factor);
+        self.cold *= factor;
+        self.total *= factor;
+    }
+
+    /// Returns the weight of all accesses, the number of accesses for an exact curve.
+    #[inline]
+    pub fn requests(&self) -> f64 {
+        self.total
+    }
+
+    /// Returns the miss ratio of an LRU cache of the given size.
+    pub fn miss_ratio(&self, size: usize) -> f64 {
+        if self.total == 0.0 {
+            return 0.0;
+        }
+        let misses: f64 = self.hist.iter().skip(size).sum();
+        (self.cold + misses) / self.total
+    }
+
+    /// Returns the miss ratios for the sizes 0 up to the size where only cold misses remain.
+    pub fn curve(&self) -> Vec<f64> {
+        if self.total == 0.0 {
+            return vec![0.0];
+        }
+        let mut misses = self.cold;
+        let mut curve = vec![0.0; self.hist.len() + 1];
+        curve[self.hist.len()] = misses / self.total;
+        for (d, w) in self.hist.iter().enumerate().rev() {
+            misses += w;
+            curve[d] = misses / self.total;
+        }
+        curve
+    }
+}
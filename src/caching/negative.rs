@@ -0,0 +1,135 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::caching::loading::CacheLoader;
+use crate::caching::two_q_lru::SimplifiedTwoQ;
+use crate::caching::{Cache, Loaded};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+/// Result of a lookup in a `NegativeCache`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lookup<'a, V> {
+    /// The key has a cached value.
+    Present(&'a V),
+    /// The key is known to have no value.
+    Absent,
+    /// Nothing is known about the key.
+    Miss,
+}
+
+/// A cache remembering the keys that have no value as well as the values.
+///
+/// Absences are kept apart from the values, in a 2Q of their own capacity,
+/// so they never evict values, and they expire after their own ttl, usually
+/// shorter than the life of the values.
+pub struct NegativeCache<K: Eq + Hash, V, C> {
+    cache: C,
+    // Expiry of the absences
+    absent: SimplifiedTwoQ<K, Instant>,
+    ttl: Duration,
+    _marker: PhantomData<V>,
+}
+
+impl<K, V, C> NegativeCache<K, V, C>
+where
+    K: Eq + Hash + Clone,
+    C: Cache<K, V>,
+{
+    /// Create a negative cache remembering up to capacity absences for ttl,
+    /// next to an empty cache of any policy.
+    pub fn new(cache: C, capacity: usize, ttl: Duration) -> NegativeCache<K, V, C> {
+        NegativeCache {
+            cache,
+            absent: SimplifiedTwoQ::with_capacity(capacity),
+            ttl,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the cache of the values.
+    #[inline]
+    pub fn cache(&self) -> &C {
+        &self.cache
+    }
+
+    /// Returns the number of absences remembered, expired ones included.
+    pub fn absent_len(&self) -> usize {
+        self.absent.len()
+    }
+
+    /// Look up a key.
+    pub fn get(&mut self, k: &K) -> Lookup<'_, V> {
+        if self.is_absent(k) {
+            return Lookup::Absent;
+        }
+        match self.cache.get(k) {
+            Some(v) => Lookup::Present(v),
+            None => Lookup::Miss,
+        }
+    }
+
+    // Returns true if the key has an absence not expired yet
+    fn is_absent(&mut self, k: &K) -> bool {
+        let expire = match self.absent.get(k) {
+            Some(&expire) => expire,
+            None => return false,
+        };
+        if expire > Instant::now() {
+            return true;
+        }
+        self.absent.remove(k);
+        false
+    }
+
+    /// Insert K-V pair to the cache, forgetting the absence of the key,
+    /// returns the entry evicted to make room for it, if any.
+    pub fn insert(&mut self, k: K, v: V) -> Option<(K, V)> {
+        self.absent.remove(&k);
+        self.cache.insert(k, v)
+    }
+
+    /// Remember that a key has no value, removing its value if any.
+    pub fn insert_absent(&mut self, k: K) {
+        self.cache.remove(&k);
+        self.absent.insert(k, Instant::now() + self.ttl);
+    }
+
+    /// Forget the value or the absence of a key.
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        self.absent.remove(k);
+        self.cache.remove(k)
+    }
+
+    /// Look up a key, calling the loader on a miss. A value loaded is
+    /// inserted, and a key the loader does not find is remembered as absent.
+    /// A loaded value the cache does not take is returned as
+    /// `Loaded::Uncached`. Errors are not cached.
+    pub fn get_or_load<L>(&mut self, k: &K, loader: &L) -> Result<Option<Loaded<'_, V>>, L::Error>
+    where
+        L: CacheLoader<K, Option<V>>,
+    {
+        if self.is_absent(k) {
+            return Ok(None);
+        }
+        if self.cache.contains(k) {
+            return Ok(self.cache.get(k).map(Loaded::Cached));
+        }
+        match loader.load(k)? {
+            Some(v) => {
+                self.absent.remove(k);
+                Ok(Some(self.cache.insert_loaded(k.clone(), v).0))
+            }
+            None => {
+                self.insert_absent(k.clone());
+                Ok(None)
+            }
+        }
+    }
+
+    /// Remove all values and absences.
+    pub fn clear(&mut self) {
+        self.absent.clear();
+        self.cache.clear();
+    }
+}
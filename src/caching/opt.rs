@@ -0,0 +1,88 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Hits and requests of an offline replay.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct OptStats {
+    /// Number of accesses in the trace.
+    pub requests: u64,
+    /// Number of accesses served from the cache.
+    pub hits: u64,
+}
+
+impl OptStats {
+    /// Returns hits / requests, or 0 for an empty trace.
+    pub fn hit_ratio(&self) -> f64 {
+        if self.requests == 0 {
+            0.0
+        } else {
+            self.hits as f64 / self.requests as f64
+        }
+    }
+}
+
+/// Belady's MIN
+/// on an access to p at time t:
+///      if p is in the cache, it is a hit
+///      else if the cache is full
+///          evict the page whose next use is the farthest in the future
+///      p.next = the time p is accessed again after t
+///
+/// The next uses are computed once for the whole trace, so the same
+/// trace can be replayed for many capacities in O(n log n) each.
+pub struct Belady<'a, K> {
+    trace: &'a [K],
+    // next_use[i] is the index of the next access to trace[i],
+    // or trace.len() + i if it is never accessed again.
+    next_use: Vec<usize>,
+}
+
+impl<'a, K: Eq + Hash> Belady<'a, K> {
+    /// Prepare a trace for offline replay.
+    pub fn new(trace: &'a [K]) -> Belady<'a, K> {
+        let n = trace.len();
+        let mut next_use = vec![0; n];
+        let mut seen: HashMap<&K, usize> = HashMap::new();
+        for (i, k) in trace.iter().enumerate().rev() {
+            next_use[i] = seen.insert(k, i).unwrap_or(n + i);
+        }
+        Belady { trace, next_use }
+    }
+
+    /// Replay the trace with the optimal policy and the given capacity.
+    pub fn simulate(&self, capacity: usize) -> OptStats {
+        let mut stats = OptStats::default();
+        // Resident keys ordered by their next use
+        let mut by_next: BTreeMap<usize, &K> = BTreeMap::new();
+        let mut resident: HashMap<&K, usize> = HashMap::new();
+        for (i, k) in self.trace.iter().enumerate() {
+            stats.requests += 1;
+            let next = self.next_use[i];
+            if let Some(cur) = resident.get_mut(k) {
+                stats.hits += 1;
+                by_next.remove(cur);
+                *cur = next;
+                by_next.insert(next, k);
+                continue;
+            }
+            if capacity == 0 {
+                continue;
+            }
+            if resident.len() >= capacity {
+                let (&farthest, &victim) = by_next.iter().next_back().unwrap();
+                by_next.remove(&farthest);
+                resident.remove(victim);
+            }
+            resident.insert(k, next);
+            by_next.insert(next, k);
+        }
+        stats
+    }
+}
+
+/// Returns the optimal hit ratio of the trace for the given capacity.
+pub fn hit_ratio<K: Eq + Hash>(trace: &[K], capacity: usize) -> f64 {
+    Belady::new(trace).simulate(capacity).hit_ratio()
+}
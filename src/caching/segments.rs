@@ -0,0 +1,414 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::caching::snapshot::{invalid, read_block, write_block, Codec};
+use crate::caching::writeback::BackingStore;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hash;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+// A segment is a header followed by one block per record:
+//
+//   header:  magic "PWSG" | version u16
+//   block:   len u32 | tag u8 | key | value | crc32 u32
+//
+// Segments are only appended to, and numbered in the order they were
+// started. The last record of a key wins, a delete record has no value.
+const MAGIC: &[u8; 4] = b"PWSG";
+const VERSION: u16 = 1;
+const HEADER_LEN: u64 = 6;
+
+const TAG_PUT: u8 = 1;
+const TAG_DELETE: u8 = 2;
+
+/// What a `SegmentStore` does with the entries of the segment it evicts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentEviction {
+    /// Drop them all.
+    Fifo,
+    /// Entries are on probation until they are read. The entries read since
+    /// they were written are appended again to the newest segment, which may
+    /// grow past `segment_size`, the others are dropped, much like entries
+    /// move from A1 to Am in 2Q.
+    Probation,
+}
+
+/// Settings of a `SegmentStore`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentConfig {
+    /// Size of a segment in bytes. A larger record gets a segment of its own.
+    pub segment_size: u64,
+    /// Number of segments kept, the oldest one is evicted beyond it.
+    pub segments: usize,
+    /// What happens to the entries of an evicted segment.
+    pub eviction: SegmentEviction,
+}
+
+impl Default for SegmentConfig {
+    fn default() -> Self {
+        SegmentConfig {
+            segment_size: 16 << 20,
+            segments: 16,
+            eviction: SegmentEviction::Fifo,
+        }
+    }
+}
+
+// Where the last value of a key is
+struct Location {
+    segment: u64,
+    offset: u64,
+    // Read since it was written
+    hit: bool,
+}
+
+struct Segment<K> {
+    file: File,
+    len: u64,
+    // Keys put in the segment, some of them were put again since
+    keys: Vec<K>,
+}
+
+/// A log-structured store of entries on disk, meant as a secondary cache on
+/// flash: entries are appended to segment files which are never rewritten,
+/// and whole segments are evicted, oldest first.
+///
+/// An index in memory maps the keys to their records. Opening a directory
+/// rebuilds it by scanning the segments, a record torn by a crash is dropped
+/// with everything after it in its segment.
+///
+/// Records are written to the files as they come, call `sync` to make them
+/// durable.
+pub struct SegmentStore<K, V> {
+    dir: PathBuf,
+    config: SegmentConfig,
+    // By number, the last one is being written
+    segments: BTreeMap<u64, Segment<K>>,
+    index: HashMap<K, Location>,
+    _marker: PhantomData<V>,
+}
+
+impl<K, V> SegmentStore<K, V>
+where
+    K: Eq + Hash + Clone + Codec,
+    V: Codec,
+{
+    /// Open the store in dir, creating it if needed.
+    pub fn open<P: AsRef<Path>>(dir: P, config: SegmentConfig) -> io::Result<SegmentStore<K, V>> {
+        assert!(config.segments > 0, "SegmentStore needs a segment");
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut ids = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            if let Some(id) = name.to_string_lossy().strip_prefix("segment.") {
+                if let Ok(id) = id.parse::<u64>() {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort_unstable();
+        let mut store = SegmentStore {
+            dir,
+            config,
+            segments: BTreeMap::new(),
+            index: HashMap::new(),
+            _marker: PhantomData,
+        };
+        for id in ids {
+            store.recover(id)?;
+        }
+        // Appending goes on in the last segment
+        if store.segments.is_empty() {
+            store.rotate()?;
+        }
+        // The limit may be lower than when the segments were written
+        while store.segments.len() > store.config.segments {
+            store.evict()?;
+        }
+        Ok(store)
+    }
+
+    // Scan a segment into the index and drop its torn tail
+    fn recover(&mut self, id: u64) -> io::Result<()> {
+        let path = self.path(id);
+        let mut r = BufReader::new(File::open(&path)?);
+        let mut header = [0; HEADER_LEN as usize];
+        if r.read_exact(&mut header).is_err() || &header[..4] != MAGIC {
+            // Crashed before the header was written
+            return fs::remove_file(&path);
+        }
+        if u16::from_le_bytes([header[4], header[5]]) != VERSION {
+            return Err(invalid("unsupported segment version"));
+        }
+        let mut len = HEADER_LEN;
+        let mut keys = vec![];
+        loop {
+            let body = match read_block(&mut r) {
+                Ok(Some(body)) => body,
+                Ok(None) => break,
+                Err(e) if is_damaged(&e) => break,
+                Err(e) => return Err(e),
+            };
+            let mut buf = &body[..];
+            let (tag, k) = match (u8::decode(&mut buf), K::decode(&mut buf)) {
+                (Ok(tag), Ok(k)) => (tag, k),
+                _ => break,
+            };
+            match tag {
+                TAG_PUT => {
+                    let loc = Location {
+                        segment: id,
+                        offset: len,
+                        hit: false,
+                    };
+                    self.index.insert(k.clone(), loc);
+                    keys.push(k);
+                }
+                TAG_DELETE => {
+                    self.index.remove(&k);
+                }
+                _ => break,
+            }
+            len += body.len() as u64 + 8;
+        }
+        let file = OpenOptions::new().read(true).write(true).open(&path)?;
+        file.set_len(len)?;
+        self.segments.insert(id, Segment { file, len, keys });
+        Ok(())
+    }
+
+    /// Returns the directory of the store.
+    #[inline]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the settings of the store.
+    #[inline]
+    pub fn config(&self) -> &SegmentConfig {
+        &self.config
+    }
+
+    /// Returns the number of entries.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns true if the store holds no entries.
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Returns true if the key has an entry.
+    pub fn contains(&self, k: &K) -> bool {
+        self.index.contains_key(k)
+    }
+
+    /// Returns the number of segments, the one being written included.
+    pub fn segments(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Returns the total size of the segments in bytes.
+    pub fn disk_len(&self) -> u64 {
+        self.segments.values().map(|s| s.len).sum()
+    }
+
+    /// Read the value of a key.
+    /// A damaged record is dropped from the index and read as a miss.
+    pub fn get(&mut self, k: &K) -> io::Result<Option<V>> {
+        let loc = match self.index.get_mut(k) {
+            Some(loc) => loc,
+            None => return Ok(None),
+        };
+        loc.hit = true;
+        let (segment, offset) = (loc.segment, loc.offset);
+        match self.read_at(segment, offset) {
+            Ok((key, v)) if key == *k => Ok(Some(v)),
+            Ok(_) => {
+                self.index.remove(k);
+                Ok(None)
+            }
+            Err(e) if is_damaged(&e) => {
+                self.index.remove(k);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Append the value of a key.
+    pub fn put(&mut self, k: K, v: &V) -> io::Result<()> {
+        let (segment, offset) = self.append(TAG_PUT, &k, Some(v))?;
+        self.put_at(k, segment, offset);
+        Ok(())
+    }
+
+    // Point the key to a put record
+    fn put_at(&mut self, k: K, segment: u64, offset: u64) {
+        let loc = Location {
+            segment,
+            offset,
+            hit: false,
+        };
+        self.index.insert(k.clone(), loc);
+        self.segments.get_mut(&segment).unwrap().keys.push(k);
+    }
+
+    /// Delete the entry of a key, returns true if it had one.
+    pub fn delete(&mut self, k: &K) -> io::Result<bool> {
+        if !self.index.contains_key(k) {
+            return Ok(false);
+        }
+        // The delete record hides the value when the index is rebuilt
+        self.append(TAG_DELETE, k, None)?;
+        self.index.remove(k);
+        Ok(true)
+    }
+
+    /// Sync the segment being written to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        match self.segments.values().next_back() {
+            Some(segment) => segment.file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("segment.{}", id))
+    }
+
+    fn read_at(&self, segment: u64, offset: u64) -> io::Result<(K, V)> {
+        let mut file = &self.segments[&segment].file;
+        file.seek(SeekFrom::Start(offset))?;
+        let body = read_block(&mut file)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        let mut buf = &body[..];
+        if u8::decode(&mut buf)? != TAG_PUT {
+            return Err(invalid("not a put record"));
+        }
+        Ok((K::decode(&mut buf)?, V::decode(&mut buf)?))
+    }
+
+    // Append a record to the last segment, starting a new one if it is full.
+    // Returns where the record was written.
+    fn append(&mut self, tag: u8, k: &K, v: Option<&V>) -> io::Result<(u64, u64)> {
+        let buf = record(tag, k, v)?;
+        let last = self.segments.values().next_back().unwrap();
+        if last.len > HEADER_LEN && last.len + buf.len() as u64 > self.config.segment_size {
+            self.rotate()?;
+        }
+        self.write_last(&buf)
+    }
+
+    // Write a record at the end of the last segment, full or not
+    fn write_last(&mut self, buf: &[u8]) -> io::Result<(u64, u64)> {
+        let (&id, segment) = self.segments.iter_mut().next_back().unwrap();
+        segment.file.seek(SeekFrom::Start(segment.len))?;
+        segment.file.write_all(buf)?;
+        let offset = segment.len;
+        segment.len += buf.len() as u64;
+        Ok((id, offset))
+    }
+
+    // Start a new segment, then evict the oldest ones beyond the limit
+    fn rotate(&mut self) -> io::Result<()> {
+        let id = self.segments.keys().next_back().map_or(0, |id| id + 1);
+        let path = self.path(id);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&VERSION.to_le_bytes())?;
+        let segment = Segment {
+            file,
+            len: HEADER_LEN,
+            keys: vec![],
+        };
+        self.segments.insert(id, segment);
+        while self.segments.len() > self.config.segments {
+            self.evict()?;
+        }
+        Ok(())
+    }
+
+    // Evict the oldest segment. Survivors are written to the last segment,
+    // past its size if need be so that evicting never rotates, and the old
+    // file is only deleted once they all are.
+    fn evict(&mut self) -> io::Result<()> {
+        let id = *self.segments.keys().next().unwrap();
+        let mut survivors = vec![];
+        if self.config.eviction == SegmentEviction::Probation {
+            for k in self.segments[&id].keys.iter() {
+                let offset = match self.index.get(k) {
+                    Some(loc) if loc.segment == id && loc.hit => loc.offset,
+                    _ => continue,
+                };
+                match self.read_at(id, offset) {
+                    Ok(kv) => survivors.push(kv),
+                    // A damaged record is dropped
+                    Err(e) if is_damaged(&e) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        // Survivors start a new probation
+        for (k, v) in survivors {
+            let (segment, offset) = self.write_last(&record(TAG_PUT, &k, Some(&v))?)?;
+            self.put_at(k, segment, offset);
+        }
+        let segment = self.segments.remove(&id).unwrap();
+        for k in segment.keys {
+            if self.index.get(&k).is_some_and(|loc| loc.segment == id) {
+                self.index.remove(&k);
+            }
+        }
+        drop(segment.file);
+        fs::remove_file(self.path(id))
+    }
+}
+
+// Encode a record as a block
+fn record<K: Codec, V: Codec>(tag: u8, k: &K, v: Option<&V>) -> io::Result<Vec<u8>> {
+    let mut body = vec![tag];
+    k.encode(&mut body);
+    if let Some(v) = v {
+        v.encode(&mut body);
+    }
+    let mut buf = Vec::with_capacity(body.len() + 8);
+    write_block(&mut buf, &body)?;
+    Ok(buf)
+}
+
+// Errors of a torn or corrupted record
+fn is_damaged(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+    )
+}
+
+impl<K, V> BackingStore<K, V> for SegmentStore<K, V>
+where
+    K: Eq + Hash + Clone + Codec,
+    V: Codec,
+{
+    type Error = io::Error;
+
+    fn read(&mut self, k: &K) -> io::Result<Option<V>> {
+        self.get(k)
+    }
+
+    fn write(&mut self, k: &K, v: &V) -> io::Result<()> {
+        self.put(k.clone(), v)
+    }
+
+    fn delete(&mut self, k: &K) -> io::Result<()> {
+        SegmentStore::delete(self, k).map(|_| ())
+    }
+}
@@ -0,0 +1,161 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::caching::mrc::{MissRatioCurve, StackDistance};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+
+// Sampling thresholds are in [0, MODULUS]
+const MODULUS: u64 = 1 << 24;
+
+#[inline]
+pub(crate) fn hash<K: Hash>(k: &K) -> u64 {
+    let mut h = DefaultHasher::new();
+    k.hash(&mut h);
+    h.finish()
+}
+
+/// Spatial sampling: a key is sampled iff hash(key) mod P < T.
+/// Every access to a sampled key is sampled, so reuse distances
+/// between the sampled keys are preserved at the rate R = T / P.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SpatialSampler {
+    threshold: u64,
+}
+
+impl SpatialSampler {
+    /// Create a sampler with a rate in (0, 1].
+    pub fn new(rate: f64) -> SpatialSampler {
+        assert!(rate > 0.0 && rate <= 1.0, "invalid sampling rate: {}", rate);
+        SpatialSampler {
+            threshold: ((rate * MODULUS as f64) as u64).max(1),
+        }
+    }
+
+    /// Returns the sampling rate R.
+    #[inline]
+    pub fn rate(&self) -> f64 {
+        self.threshold as f64 / MODULUS as f64
+    }
+
+    /// Returns true if the key is sampled.
+    #[inline]
+    pub fn sample<K: Hash>(&self, k: &K) -> bool {
+        self.sample_hash(hash(k))
+    }
+
+    // Same as sample, for a key already hashed with hash()
+    #[inline]
+    pub(crate) fn sample_hash(&self, h: u64) -> bool {
+        h % MODULUS < self.threshold
+    }
+}
+
+/// SHARDS
+/// for each access to key L:
+///      T_i = hash(L) mod P
+///      if T_i < T
+///          process L with the standard stack algorithm,
+///          scaling its reuse distance by 1 / R
+///
+/// The fixed-rate variant keeps T constant. The fixed-size variant tracks
+/// at most s_max keys: when a new key exceeds the bound, the keys with the
+/// largest T_i are evicted, T is lowered to that T_i and the histogram is
+/// rescaled by R_new / R_old.
+pub struct Shards {
+    threshold: u64,
+    // Keys are identified by their 64-bit hash
+    stack: StackDistance<u64>,
+    mrc: MissRatioCurve,
+    requests: u64,
+    // Fixed-size variant only: bound and tracked keys ordered by T_i
+    s_max: Option<usize>,
+    tracked: BTreeSet<(u64, u64)>,
+}
+
+impl Shards {
+    /// Create a fixed-rate SHARDS with a rate in (0, 1].
+    pub fn fixed_rate(rate: f64) -> Shards {
+        Self::build(SpatialSampler::new(rate).threshold, None)
+    }
+
+    /// Create a fixed-size SHARDS tracking at most s_max keys,
+    /// starting with a sampling rate of 1.
+    pub fn fixed_size(s_max: usize) -> Shards {
+        assert!(s_max > 0, "SHARDS needs to track at least one key");
+        Self::build(MODULUS, Some(s_max))
+    }
+
+    fn build(threshold: u64, s_max: Option<usize>) -> Shards {
+        Shards {
+            threshold,
+            stack: StackDistance::new(),
+            mrc: MissRatioCurve::default(),
+            requests: 0,
+            s_max,
+            tracked: BTreeSet::new(),
+        }
+    }
+
+    /// Returns the current sampling rate R.
+    #[inline]
+    pub fn rate(&self) -> f64 {
+        self.threshold as f64 / MODULUS as f64
+    }
+
+    /// Returns the number of keys being tracked.
+    #[inline]
+    pub fn tracked(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Process an access.
+    pub fn access<K: Hash>(&mut self, k: &K) {
+        self.requests += 1;
+        let full = hash(k);
+        let t = full % MODULUS;
+        if t >= self.threshold {
+            return;
+        }
+        let rate = self.rate();
+        let distance = self.stack.access(full);
+        self.mrc
+            .record(distance.map(|d| (d as f64 / rate) as usize), 1.0);
+        if let Some(s_max) = self.s_max {
+            if distance.is_none() {
+                self.tracked.insert((t, full));
+                if self.tracked.len() > s_max {
+                    self.lower_threshold();
+                }
+            }
+        }
+    }
+
+    // Evict the keys with the largest T_i and lower T to it
+    fn lower_threshold(&mut self) {
+        let &(t_max, _) = self.tracked.iter().next_back().unwrap();
+        while let Some(&(t, full)) = self.tracked.iter().next_back() {
+            if t != t_max {
+                break;
+            }
+            self.tracked.remove(&(t, full));
+            self.stack.forget(&full);
+        }
+        let old_rate = self.rate();
+        self.threshold = t_max;
+        self.mrc.scale(self.rate() / old_rate);
+    }
+
+    /// Returns the estimated miss-ratio curve.
+    /// The fixed-rate variant is corrected with SHARDS-adj: the difference
+    /// between the expected and the actual number of samples is added to
+    /// the smallest distance.
+    pub fn mrc(&self) -> MissRatioCurve {
+        let mut mrc = self.mrc.clone();
+        if self.s_max.is_none() {
+            let diff = self.requests as f64 * self.rate() - mrc.requests();
+            mrc.record(Some(0), diff);
+        }
+        mrc
+    }
+}
@@ -0,0 +1,203 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::caching::admission::TinyLfu;
+use crate::caching::gdsf::GdsfCache;
+use crate::caching::lfu::LfuCache;
+use crate::caching::mq::MultiQueue;
+use crate::caching::two_q_lru::{Adaptive, SimplifiedTwoQ};
+use crate::caching::{Cache, Insertion};
+use std::borrow::Borrow;
+use std::hash::Hash;
+
+/// An access to a key in a trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Access<K> {
+    /// The accessed key.
+    pub key: K,
+    /// Size of the accessed object in bytes, 1 if the trace has no sizes.
+    pub size: u64,
+}
+
+impl<K> Access<K> {
+    /// Create an access of size 1.
+    pub fn new(key: K) -> Access<K> {
+        Access { key, size: 1 }
+    }
+
+    /// Create an access with the size of the object.
+    pub fn with_size(key: K, size: u64) -> Access<K> {
+        Access { key, size }
+    }
+}
+
+/// Counters collected while replaying a trace.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Stats {
+    /// Number of accesses.
+    pub requests: u64,
+    /// Number of accesses served from the cache.
+    pub hits: u64,
+    /// Total size of all accesses.
+    pub bytes: u64,
+    /// Total size of the accesses served from the cache.
+    pub hit_bytes: u64,
+    /// Number of entries evicted by the policy.
+    pub evictions: u64,
+    /// Number of missed entries the policy did not take.
+    pub rejections: u64,
+}
+
+impl Stats {
+    /// Returns hits / requests, or 0 for an empty trace.
+    pub fn hit_ratio(&self) -> f64 {
+        ratio(self.hits, self.requests)
+    }
+
+    /// Returns hit bytes / bytes, or 0 for an empty trace.
+    pub fn byte_hit_ratio(&self) -> f64 {
+        ratio(self.hit_bytes, self.bytes)
+    }
+}
+
+#[inline]
+fn ratio(a: u64, b: u64) -> f64 {
+    if b == 0 {
+        0.0
+    } else {
+        a as f64 / b as f64
+    }
+}
+
+/// Replay a trace against a cache.
+/// Every access is a `get`, and a miss is followed by a `try_insert_sized`
+/// with the size of the access.
+pub fn replay<K, C, I>(cache: &mut C, trace: I) -> Stats
+where
+    K: Clone,
+    C: Cache<K, ()> + ?Sized,
+    I: IntoIterator,
+    I::Item: Borrow<Access<K>>,
+{
+    let mut stats = Stats::default();
+    for access in trace {
+        let access = access.borrow();
+        stats.requests += 1;
+        stats.bytes += access.size;
+        if cache.get(&access.key).is_some() {
+            stats.hits += 1;
+            stats.hit_bytes += access.size;
+        } else {
+            let len = cache.len();
+            match cache.try_insert_sized(access.key.clone(), (), access.size) {
+                Ok(Insertion::Inserted) => {}
+                // A large entry may evict several
+                Ok(Insertion::Evicted(..)) => stats.evictions += (len + 1 - cache.len()) as u64,
+                Ok(Insertion::Rejected(..)) | Err(_) => stats.rejections += 1,
+            }
+        }
+    }
+    stats
+}
+
+/// Creates an empty cache of the given capacity.
+pub type Factory<K> = Box<dyn Fn(usize) -> Box<dyn Cache<K, ()>>>;
+
+/// Names of the policies known by `builtin`.
+pub const BUILTIN: &[&str] = &[
+    "lfu",
+    "2q-simple",
+    "2q-adaptive",
+    "2q-tinylfu",
+    "mq",
+    "gdsf",
+];
+
+/// Returns the factory of a policy implemented in this crate.
+/// The capacity counts entries, except for gdsf where it is the total
+/// size of the entries. Both are the same with a trace without sizes.
+pub fn builtin<K: Eq + Hash + 'static>(name: &str) -> Option<Factory<K>> {
+    let factory: Factory<K> = match name {
+        "lfu" => Box::new(|cap| Box::new(LfuCache::new(cap))),
+        "2q-simple" => Box::new(|cap| Box::new(SimplifiedTwoQ::with_capacity(cap))),
+        "2q-adaptive" => {
+            Box::new(|cap| Box::new(SimplifiedTwoQ::with_adaptive(cap, Adaptive::default())))
+        }
+        "2q-tinylfu" => Box::new(|cap| {
+            Box::new(SimplifiedTwoQ::with_capacity(cap).with_admission(TinyLfu::new(cap)))
+        }),
+        "mq" => Box::new(|cap| Box::new(MultiQueue::with_capacity(cap))),
+        "gdsf" => Box::new(|cap| Box::new(GdsfCache::new(cap as u64))),
+        _ => return None,
+    };
+    Some(factory)
+}
+
+/// Stats of one policy with one capacity.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    /// Name of the policy.
+    pub policy: String,
+    /// Capacity of the cache in entries.
+    pub capacity: usize,
+    /// Counters of the replay.
+    pub stats: Stats,
+}
+
+/// Replays a trace against several policies and capacities.
+pub struct Simulator<K> {
+    policies: Vec<(String, Factory<K>)>,
+}
+
+impl<K> Default for Simulator<K> {
+    fn default() -> Self {
+        Simulator { policies: vec![] }
+    }
+}
+
+impl<K: Clone> Simulator<K> {
+    /// Create a simulator without policies.
+    pub fn new() -> Simulator<K> {
+        Self::default()
+    }
+
+    /// Add a policy to compare.
+    pub fn policy<F>(mut self, name: &str, factory: F) -> Simulator<K>
+    where
+        F: Fn(usize) -> Box<dyn Cache<K, ()>> + 'static,
+    {
+        self.policies.push((name.to_string(), Box::new(factory)));
+        self
+    }
+
+    /// Returns the names of the policies, in the order they were added.
+    pub fn policies(&self) -> impl Iterator<Item = &str> {
+        self.policies.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Replay the trace with a fresh cache for every policy and capacity.
+    /// Reports are ordered by policy, then by capacity.
+    pub fn run(&self, trace: &[Access<K>], capacities: &[usize]) -> Vec<Report> {
+        let mut reports = vec![];
+        for (name, factory) in self.policies.iter() {
+            for &capacity in capacities {
+                let mut cache = factory(capacity);
+                reports.push(Report {
+                    policy: name.clone(),
+                    capacity,
+                    stats: replay(cache.as_mut(), trace),
+                });
+            }
+        }
+        reports
+    }
+}
+
+impl<K: Clone + Eq + Hash + 'static> Simulator<K> {
+    /// Create a simulator comparing all the policies implemented in this crate.
+    pub fn with_builtin() -> Simulator<K> {
+        BUILTIN.iter().fold(Simulator::new(), |sim, &name| {
+            let factory = builtin(name).unwrap();
+            sim.policy(name, factory)
+        })
+    }
+}
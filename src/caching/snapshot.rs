@@ -0,0 +1,440 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::caching::lfu::LfuCache;
+use crate::caching::two_q_lru::SimplifiedTwoQ;
+use crate::common::crc32::crc32;
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+// A snapshot is a header followed by blocks:
+//
+//   header:  magic "PWLS" | version u16 | kind u8
+//   block:   len u32 | body (len bytes) | crc32(body) u32
+//   body:    tag u8 | payload
+//
+// The first block holds the policy metadata, then come blocks of entries,
+// and the last block holds the number of entries. Integers are little endian.
+const MAGIC: &[u8; 4] = b"PWLS";
+const VERSION: u16 = 1;
+
+const KIND_LFU: u8 = 1;
+const KIND_TWO_Q: u8 = 2;
+
+const TAG_META: u8 = 1;
+const TAG_ENTRIES: u8 = 2;
+const TAG_END: u8 = 3;
+
+// Entries are flushed in blocks of about this size
+const BLOCK_SIZE: usize = 64 * 1024;
+// Larger blocks can only come from a corrupted length
+const MAX_BLOCK: usize = 1 << 30;
+
+#[inline]
+pub(crate) fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+#[inline]
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if buf.len() < n {
+        return Err(invalid("unexpected end of block"));
+    }
+    let (head, tail) = buf.split_at(n);
+    *buf = tail;
+    Ok(head)
+}
+
+/// Binary encoding of keys and values in snapshots and logs.
+pub trait Codec: Sized {
+    /// Append the encoding of self to buf.
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    /// Decode a value from the front of buf and advance it.
+    fn decode(buf: &mut &[u8]) -> io::Result<Self>;
+}
+
+macro_rules! int_codec {
+    ($($t:ty),*) => {
+        $(
+            impl Codec for $t {
+                fn encode(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+                    let mut bytes = [0; std::mem::size_of::<$t>()];
+                    bytes.copy_from_slice(take(buf, std::mem::size_of::<$t>())?);
+                    Ok(<$t>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+int_codec!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl Codec for usize {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (*self as u64).encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        Ok(u64::decode(buf)? as usize)
+    }
+}
+
+impl Codec for bool {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        match u8::decode(buf)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("invalid bool")),
+        }
+    }
+}
+
+impl Codec for () {
+    fn encode(&self, _: &mut Vec<u8>) {}
+
+    fn decode(_: &mut &[u8]) -> io::Result<Self> {
+        Ok(())
+    }
+}
+
+impl Codec for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as u32).encode(buf);
+        buf.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        let len = u32::decode(buf)? as usize;
+        String::from_utf8(take(buf, len)?.to_vec()).map_err(|_| invalid("invalid string"))
+    }
+}
+
+impl<T: Codec> Codec for Vec<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as u32).encode(buf);
+        self.iter().for_each(|t| t.encode(buf));
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        let len = u32::decode(buf)? as usize;
+        // Every element takes at least one byte, except for zero-sized ones
+        let mut v = Vec::with_capacity(len.min(buf.len()));
+        for _ in 0..len {
+            v.push(T::decode(buf)?);
+        }
+        Ok(v)
+    }
+}
+
+impl<T: Codec> Codec for Option<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.is_some().encode(buf);
+        if let Some(t) = self {
+            t.encode(buf);
+        }
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        if bool::decode(buf)? {
+            Ok(Some(T::decode(buf)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<A: Codec, B: Codec> Codec for (A, B) {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.0.encode(buf);
+        self.1.encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        Ok((A::decode(buf)?, B::decode(buf)?))
+    }
+}
+
+// Write a framed block
+pub(crate) fn write_block<W: Write>(w: &mut W, body: &[u8]) -> io::Result<()> {
+    w.write_all(&(body.len() as u32).to_le_bytes())?;
+    w.write_all(body)?;
+    w.write_all(&crc32(body).to_le_bytes())
+}
+
+// Read a framed block and check its CRC.
+// Returns None at a clean end of file, before the length of a block.
+pub(crate) fn read_block<R: Read>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match r.read(&mut len[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_BLOCK {
+        return Err(invalid("block too large"));
+    }
+    let mut body = vec![0; len];
+    r.read_exact(&mut body)?;
+    let mut crc = [0; 4];
+    r.read_exact(&mut crc)?;
+    if crc32(&body) != u32::from_le_bytes(crc) {
+        return Err(invalid("block checksum mismatch"));
+    }
+    Ok(Some(body))
+}
+
+// Streams entries into blocks of about BLOCK_SIZE bytes
+struct EntryWriter<W> {
+    w: W,
+    body: Vec<u8>,
+    count: u32,
+    total: u64,
+    // The first error, later entries are skipped
+    err: Option<io::Error>,
+}
+
+impl<W: Write> EntryWriter<W> {
+    fn new(mut w: W, kind: u8, meta: &[u8]) -> io::Result<EntryWriter<W>> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&[kind])?;
+        let mut body = vec![TAG_META];
+        body.extend_from_slice(meta);
+        write_block(&mut w, &body)?;
+        Ok(EntryWriter {
+            w,
+            body: Vec::with_capacity(BLOCK_SIZE),
+            count: 0,
+            total: 0,
+            err: None,
+        })
+    }
+
+    fn entry<F: FnOnce(&mut Vec<u8>)>(&mut self, f: F) {
+        if self.err.is_some() {
+            return;
+        }
+        if self.body.is_empty() {
+            self.body.push(TAG_ENTRIES);
+            self.body.extend_from_slice(&0u32.to_le_bytes());
+        }
+        f(&mut self.body);
+        self.count += 1;
+        self.total += 1;
+        if self.body.len() >= BLOCK_SIZE {
+            if let Err(e) = self.flush() {
+                self.err = Some(e);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.count == 0 {
+            return Ok(());
+        }
+        self.body[1..5].copy_from_slice(&self.count.to_le_bytes());
+        write_block(&mut self.w, &self.body)?;
+        self.body.clear();
+        self.count = 0;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        if let Some(e) = self.err.take() {
+            return Err(e);
+        }
+        self.flush()?;
+        let mut body = vec![TAG_END];
+        self.total.encode(&mut body);
+        write_block(&mut self.w, &body)?;
+        self.w.flush()
+    }
+}
+
+// Reads the blocks written by EntryWriter
+struct EntryReader<R> {
+    r: R,
+    total: u64,
+}
+
+impl<R: Read> EntryReader<R> {
+    // Check the header and return the metadata
+    fn new(mut r: R, kind: u8) -> io::Result<(EntryReader<R>, Vec<u8>)> {
+        let mut header = [0; 7];
+        r.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(invalid("not a snapshot"));
+        }
+        if u16::from_le_bytes([header[4], header[5]]) != VERSION {
+            return Err(invalid("unsupported snapshot version"));
+        }
+        if header[6] != kind {
+            return Err(invalid("snapshot of another policy"));
+        }
+        let mut reader = EntryReader { r, total: 0 };
+        let meta = reader.block(TAG_META)?;
+        Ok((reader, meta))
+    }
+
+    fn block(&mut self, tag: u8) -> io::Result<Vec<u8>> {
+        let mut body = read_block(&mut self.r)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        if body.first() != Some(&tag) {
+            return Err(invalid("unexpected block"));
+        }
+        body.remove(0);
+        Ok(body)
+    }
+
+    // Decode every entry until the end block
+    fn entries<F>(&mut self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&mut &[u8]) -> io::Result<()>,
+    {
+        loop {
+            let body = read_block(&mut self.r)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+            let mut buf = &body[..];
+            match u8::decode(&mut buf)? {
+                TAG_ENTRIES => {
+                    let count = u32::decode(&mut buf)?;
+                    for _ in 0..count {
+                        f(&mut buf)?;
+                    }
+                    self.total += count as u64;
+                }
+                TAG_END => {
+                    if u64::decode(&mut buf)? != self.total {
+                        return Err(invalid("entry count mismatch"));
+                    }
+                    return Ok(());
+                }
+                _ => return Err(invalid("unexpected block")),
+            }
+        }
+    }
+}
+
+/// A cache that can be written to and read back from a binary snapshot.
+/// Snapshots are versioned, every block carries a CRC32, and truncated or
+/// corrupted snapshots are rejected with an `InvalidData` or `UnexpectedEof` error.
+pub trait Snapshot: Sized {
+    /// Write the entries and the policy state.
+    fn write_snapshot<W: Write>(&self, w: W) -> io::Result<()>;
+
+    /// Read a cache equivalent to the one that was written.
+    fn read_snapshot<R: Read>(r: R) -> io::Result<Self>;
+
+    /// Returns false if the cache holds state that is not written,
+    /// so that reading its snapshot back gives a different cache.
+    fn is_snapshot_complete(&self) -> bool {
+        true
+    }
+}
+
+/// Entries are written with their frequency, in eviction order.
+/// Pinned entries are written last and read back unpinned.
+/// The admission policy is not written, such a snapshot is incomplete.
+impl<K: Eq + Hash + Codec, V: Codec> Snapshot for LfuCache<K, V> {
+    fn write_snapshot<W: Write>(&self, w: W) -> io::Result<()> {
+        let mut meta = vec![];
+        self.capacity().encode(&mut meta);
+        let mut writer = EntryWriter::new(w, KIND_LFU, &meta)?;
+        self.for_each_entry(|k, v, times| {
+            writer.entry(|buf| {
+                k.encode(buf);
+                v.encode(buf);
+                times.encode(buf);
+            })
+        });
+        writer.finish()
+    }
+
+    fn read_snapshot<R: Read>(r: R) -> io::Result<Self> {
+        let (mut reader, meta) = EntryReader::new(r, KIND_LFU)?;
+        let mut cache = LfuCache::new(usize::decode(&mut &meta[..])?);
+        reader.entries(|buf| {
+            let k = K::decode(buf)?;
+            let v = V::decode(buf)?;
+            cache.restore(k, v, u64::decode(buf)?);
+            Ok(())
+        })?;
+        Ok(cache)
+    }
+
+    fn is_snapshot_complete(&self) -> bool {
+        !self.has_learned_state()
+    }
+}
+
+/// A1 and Am are written from tail to head with the A1 threshold.
+/// The admission policy and the shadow caches of an adaptive 2Q are not
+/// written, such a snapshot is incomplete.
+/// Pinned entries are written last and read back unpinned.
+impl<K: Eq + Hash + Codec, V: Codec> Snapshot for SimplifiedTwoQ<K, V> {
+    fn write_snapshot<W: Write>(&self, w: W) -> io::Result<()> {
+        let mut meta = vec![];
+        self.capacity().encode(&mut meta);
+        self.threshold().encode(&mut meta);
+        let mut writer = EntryWriter::new(w, KIND_TWO_Q, &meta)?;
+        self.for_each_entry(|k, v, at_am| {
+            writer.entry(|buf| {
+                at_am.encode(buf);
+                k.encode(buf);
+                v.encode(buf);
+            })
+        });
+        writer.finish()
+    }
+
+    fn read_snapshot<R: Read>(r: R) -> io::Result<Self> {
+        let (mut reader, meta) = EntryReader::new(r, KIND_TWO_Q)?;
+        let mut meta = &meta[..];
+        let cap = usize::decode(&mut meta)?;
+        let threshold = usize::decode(&mut meta)?;
+        let mut cache = SimplifiedTwoQ::with_threshold(cap, threshold);
+        reader.entries(|buf| {
+            let at_am = bool::decode(buf)?;
+            let k = K::decode(buf)?;
+            cache.restore(k, V::decode(buf)?, at_am);
+            Ok(())
+        })?;
+        Ok(cache)
+    }
+
+    fn is_snapshot_complete(&self) -> bool {
+        !self.has_learned_state()
+    }
+}
+
+/// Write a snapshot to a temporary file, sync it and rename it over path,
+/// so path always holds a complete snapshot.
+pub fn save<S: Snapshot, P: AsRef<Path>>(cache: &S, path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let file = File::create(&tmp)?;
+    let mut w = BufWriter::new(file);
+    cache.write_snapshot(&mut w)?;
+    w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// Read a snapshot written by `save`.
+pub fn load<S: Snapshot, P: AsRef<Path>>(path: P) -> io::Result<S> {
+    S::read_snapshot(BufReader::new(File::open(path)?))
+}
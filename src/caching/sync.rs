@@ -0,0 +1,220 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::caching::Cache;
+use std::any::Any;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+/// Error of `SharedCache::get_or_load`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError<E> {
+    /// The loader returned an error.
+    Failed(E),
+    /// The loader panicked, with the panic message.
+    Panicked(String),
+}
+
+impl<E: Display> Display for LoadError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Failed(e) => write!(f, "load failed: {}", e),
+            LoadError::Panicked(msg) => write!(f, "loader panicked: {}", msg),
+        }
+    }
+}
+
+impl<E: Error> Error for LoadError<E> {}
+
+type LoadResult<V, E> = Result<V, LoadError<E>>;
+
+// A load in progress, waited on by the callers that missed the same key
+struct Flight<V, E> {
+    result: Mutex<Option<LoadResult<V, E>>>,
+    done: Condvar,
+}
+
+impl<V: Clone, E: Clone> Flight<V, E> {
+    fn wait(&self) -> LoadResult<V, E> {
+        let mut result = self.result.lock().unwrap();
+        while result.is_none() {
+            result = self.done.wait(result).unwrap();
+        }
+        result.clone().unwrap()
+    }
+
+    fn finish(&self, res: LoadResult<V, E>) {
+        *self.result.lock().unwrap() = Some(res);
+        self.done.notify_all();
+    }
+}
+
+// What a caller missing a key does in single-flight mode
+enum Role<V, E> {
+    Wait(Arc<Flight<V, E>>),
+    Lead(Option<Arc<Flight<V, E>>>),
+}
+
+/// A cache shared between threads, behind a mutex.
+/// Values are cloned out of the cache, so the lock is never held by the caller.
+///
+/// In single-flight mode, concurrent `get_or_load` calls missing the same key
+/// wait for one load and all receive its result. Loaders always run without
+/// the lock, so a panicking loader never poisons the cache.
+pub struct SharedCache<K, V, C> {
+    cache: Mutex<C>,
+    single_flight: bool,
+    // Loads in progress, the flight type depends on the error type of the loader
+    flights: Mutex<HashMap<K, Arc<dyn Any + Send + Sync>>>,
+    _marker: PhantomData<fn(V) -> V>,
+}
+
+impl<K, V, C> SharedCache<K, V, C>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    C: Cache<K, V>,
+{
+    /// Share a cache of any policy.
+    pub fn new(cache: C) -> SharedCache<K, V, C> {
+        SharedCache {
+            cache: Mutex::new(cache),
+            single_flight: false,
+            flights: Mutex::new(HashMap::new()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Coalesce concurrent loads of the same key.
+    pub fn single_flight(mut self) -> SharedCache<K, V, C> {
+        self.single_flight = true;
+        self
+    }
+
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, C> {
+        self.cache.lock().unwrap()
+    }
+
+    /// Run f with the cache locked.
+    pub fn with<R, F: FnOnce(&mut C) -> R>(&self, f: F) -> R {
+        f(&mut self.lock())
+    }
+
+    /// Get a clone of the value with key.
+    pub fn get(&self, k: &K) -> Option<V> {
+        self.lock().get(k).cloned()
+    }
+
+    /// Insert K-V pair to the cache,
+    /// returns the entry evicted to make room for it, if any.
+    pub fn insert(&self, k: K, v: V) -> Option<(K, V)> {
+        self.lock().insert(k, v)
+    }
+
+    /// Remove an entry from the cache, returns its value.
+    pub fn remove(&self, k: &K) -> Option<V> {
+        self.lock().remove(k)
+    }
+
+    /// Returns true if the key is cached, without counting it as an access.
+    pub fn contains(&self, k: &K) -> bool {
+        self.lock().contains(k)
+    }
+
+    /// Returns the number of elements in the cache.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Returns true if the cache contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Returns the number of elements the cache can hold.
+    pub fn capacity(&self) -> usize {
+        self.lock().capacity()
+    }
+
+    /// Remove all data in the cache.
+    pub fn clear(&self) {
+        self.lock().clear()
+    }
+
+    /// Get a clone of the value with key, loading and inserting it on a miss.
+    /// Errors are not cached. A panic of the loader is caught and returned
+    /// as `LoadError::Panicked`.
+    pub fn get_or_load<F, E>(&self, k: &K, load: F) -> LoadResult<V, E>
+    where
+        F: FnOnce(&K) -> Result<V, E>,
+        K: Send + 'static,
+        V: Send + 'static,
+        E: Clone + Send + 'static,
+    {
+        if let Some(v) = self.get(k) {
+            return Ok(v);
+        }
+        if !self.single_flight {
+            let v = run(k, load)?;
+            self.insert(k.clone(), v.clone());
+            return Ok(v);
+        }
+
+        let role = {
+            let mut flights = self.flights.lock().unwrap();
+            // The value may have been loaded since the first lookup
+            if let Some(v) = self.get(k) {
+                return Ok(v);
+            }
+            match flights.get(k).cloned().map(Arc::downcast::<Flight<V, E>>) {
+                Some(Ok(flight)) => Role::Wait(flight),
+                // A flight with another error type, not shared
+                Some(Err(_)) => Role::Lead(None),
+                None => {
+                    let flight = Arc::new(Flight {
+                        result: Mutex::new(None),
+                        done: Condvar::new(),
+                    });
+                    flights.insert(k.clone(), flight.clone());
+                    Role::Lead(Some(flight))
+                }
+            }
+        };
+        let flight = match role {
+            Role::Wait(flight) => return flight.wait(),
+            Role::Lead(flight) => flight,
+        };
+        let res = run(k, load);
+        if let Ok(v) = &res {
+            self.insert(k.clone(), v.clone());
+        }
+        if let Some(flight) = flight {
+            // Later callers find the value in the cache
+            self.flights.lock().unwrap().remove(k);
+            flight.finish(res.clone());
+        }
+        res
+    }
+}
+
+// Run a loader, turning a panic into an error
+fn run<K, V, E, F: FnOnce(&K) -> Result<V, E>>(k: &K, load: F) -> LoadResult<V, E> {
+    match panic::catch_unwind(AssertUnwindSafe(|| load(k))) {
+        Ok(res) => res.map_err(LoadError::Failed),
+        Err(payload) => {
+            let msg = if let Some(s) = payload.downcast_ref::<&str>() {
+                s.to_string()
+            } else if let Some(s) = payload.downcast_ref::<String>() {
+                s.clone()
+            } else {
+                "unknown panic".to_string()
+            };
+            Err(LoadError::Panicked(msg))
+        }
+    }
+}
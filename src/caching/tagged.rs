@@ -0,0 +1,170 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::caching::{AllPinned, Cache, Insertion};
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+/// A cache whose entries carry tags, so that all the entries of a tag can be
+/// invalidated together. Entries are read and written through `Cache`.
+///
+/// An index from tags to keys is kept next to the cache of any policy and
+/// cleaned up as entries are replaced, removed and evicted, so
+/// `invalidate_tag` takes time proportional to the number of entries of the tag.
+pub struct TaggedCache<K, V, T, C> {
+    cache: C,
+    keys: HashMap<T, HashSet<K>>,
+    tags: HashMap<K, Vec<T>>,
+    _marker: PhantomData<V>,
+}
+
+impl<K, V, T, C> TaggedCache<K, V, T, C>
+where
+    K: Eq + Hash + Clone,
+    T: Eq + Hash + Clone,
+    C: Cache<K, V>,
+{
+    /// Tag the entries of an empty cache of any policy.
+    pub fn new(cache: C) -> TaggedCache<K, V, T, C> {
+        TaggedCache {
+            cache,
+            keys: HashMap::new(),
+            tags: HashMap::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the wrapped cache.
+    #[inline]
+    pub fn cache(&self) -> &C {
+        &self.cache
+    }
+
+    /// Insert K-V pair to the cache with tags, replacing the tags of the key,
+    /// returns the entry evicted to make room for it, if any.
+    pub fn insert_tagged<I: IntoIterator<Item = T>>(
+        &mut self,
+        k: K,
+        v: V,
+        tags: I,
+    ) -> Option<(K, V)> {
+        self.try_insert_tagged(k, v, tags)
+            .ok()
+            .and_then(Insertion::evicted)
+    }
+
+    /// Insert K-V pair to the cache with tags like `Cache::try_insert`,
+    /// replacing the tags of the key.
+    pub fn try_insert_tagged<I: IntoIterator<Item = T>>(
+        &mut self,
+        k: K,
+        v: V,
+        tags: I,
+    ) -> Result<Insertion<K, V>, AllPinned<K, V>> {
+        self.untag(&k);
+        let mut key_tags: Vec<T> = vec![];
+        for tag in tags {
+            if !key_tags.contains(&tag) {
+                self.keys.entry(tag.clone()).or_default().insert(k.clone());
+                key_tags.push(tag);
+            }
+        }
+        if !key_tags.is_empty() {
+            self.tags.insert(k.clone(), key_tags);
+        }
+        let insertion = self.cache.try_insert(k, v);
+        // The evicted entry, or the new one if the cache does not take it
+        if let Ok(Insertion::Evicted(k, _)) | Ok(Insertion::Rejected(k, _)) | Err(AllPinned(k, _)) =
+            &insertion
+        {
+            self.untag(k);
+        }
+        insertion
+    }
+
+    /// Remove all the entries carrying a tag, returns how many were removed.
+    pub fn invalidate_tag(&mut self, tag: &T) -> usize {
+        let keys = match self.keys.remove(tag) {
+            Some(keys) => keys,
+            None => return 0,
+        };
+        let mut removed = 0;
+        for k in keys {
+            self.untag(&k);
+            if self.cache.remove(&k).is_some() {
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Returns the tags of a key.
+    pub fn tags(&self, k: &K) -> &[T] {
+        self.tags.get(k).map_or(&[], |tags| &tags[..])
+    }
+
+    /// Returns the number of entries carrying a tag.
+    pub fn tag_len(&self, tag: &T) -> usize {
+        self.keys.get(tag).map_or(0, |keys| keys.len())
+    }
+
+    // Remove a key from the index
+    fn untag(&mut self, k: &K) {
+        for tag in self.tags.remove(k).unwrap_or_default() {
+            // The keys of an invalidated tag are already gone
+            if let Some(keys) = self.keys.get_mut(&tag) {
+                keys.remove(k);
+                if keys.is_empty() {
+                    self.keys.remove(&tag);
+                }
+            }
+        }
+    }
+}
+
+/// Entries inserted through `Cache::insert` have no tags.
+impl<K, V, T, C> Cache<K, V> for TaggedCache<K, V, T, C>
+where
+    K: Eq + Hash + Clone,
+    T: Eq + Hash + Clone,
+    C: Cache<K, V>,
+{
+    fn get(&mut self, k: &K) -> Option<&V> {
+        self.cache.get(k)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        self.cache.peek(k)
+    }
+
+    fn insert(&mut self, k: K, v: V) -> Option<(K, V)> {
+        self.insert_tagged(k, v, None)
+    }
+
+    fn try_insert(&mut self, k: K, v: V) -> Result<Insertion<K, V>, AllPinned<K, V>> {
+        self.try_insert_tagged(k, v, None)
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        self.untag(k);
+        self.cache.remove(k)
+    }
+
+    fn contains(&self, k: &K) -> bool {
+        self.cache.contains(k)
+    }
+
+    fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.cache.capacity()
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+        self.tags.clear();
+        self.cache.clear();
+    }
+}
@@ -0,0 +1,316 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::caching::snapshot::{self, Codec};
+use crate::caching::{AllPinned, Cache, Insertion, Loaded};
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+// Every entry of a disk tier is a file `entry.N` holding one block:
+//
+//   block:   len u32 | key | value | crc32 u32
+//
+// N is never reused, so a new value of a key never overwrites the old file.
+
+/// A cache tier on disk, one file per entry in a directory.
+/// Its policy maps the keys to the numbers of their files, so any policy
+/// with any capacity can replace the entries.
+///
+/// Entries survive restarts: opening a directory inserts the intact entries
+/// into the policy, in no particular order, and deletes the damaged ones.
+pub struct DiskTier<K, V, C> {
+    dir: PathBuf,
+    policy: C,
+    next: u64,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K, V, C> DiskTier<K, V, C>
+where
+    K: Eq + Hash + Clone + Codec,
+    V: Codec,
+    C: Cache<K, u64>,
+{
+    /// Open the tier in dir, creating it if needed, with an empty policy.
+    pub fn open<P: AsRef<Path>>(dir: P, policy: C) -> io::Result<DiskTier<K, V, C>> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut tier = DiskTier {
+            dir,
+            policy,
+            next: 0,
+            _marker: PhantomData,
+        };
+        let mut files = vec![];
+        for entry in fs::read_dir(&tier.dir)? {
+            let name = entry?.file_name();
+            if let Some(n) = name.to_string_lossy().strip_prefix("entry.") {
+                if let Ok(n) = n.parse::<u64>() {
+                    files.push(n);
+                }
+            }
+        }
+        // A key written twice keeps its newer file
+        files.sort_unstable();
+        for n in files {
+            tier.next = n + 1;
+            match tier.read(n) {
+                Ok((k, _)) => {
+                    tier.index(k, n)?;
+                }
+                Err(e) if is_damaged(&e) => tier.delete(n)?,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(tier)
+    }
+
+    /// Returns the directory of the tier.
+    #[inline]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the policy of the tier.
+    #[inline]
+    pub fn policy(&self) -> &C {
+        &self.policy
+    }
+
+    /// Returns the number of entries on disk.
+    pub fn len(&self) -> usize {
+        self.policy.len()
+    }
+
+    /// Returns true if the tier holds no entries.
+    pub fn is_empty(&self) -> bool {
+        self.policy.is_empty()
+    }
+
+    /// Returns true if the key is on disk, without counting it as an access.
+    pub fn contains(&self, k: &K) -> bool {
+        self.policy.contains(k)
+    }
+
+    /// Read the value of a key, counting it as an access.
+    /// A damaged entry is deleted and read as a miss.
+    pub fn get(&mut self, k: &K) -> io::Result<Option<V>> {
+        let n = match self.policy.get(k) {
+            Some(&n) => n,
+            None => return Ok(None),
+        };
+        match self.read(n) {
+            Ok((key, v)) if key == *k => Ok(Some(v)),
+            Ok(_) => {
+                self.remove(k)?;
+                Ok(None)
+            }
+            Err(e) if is_damaged(&e) => {
+                self.remove(k)?;
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write K-V pair to disk, deleting the entry evicted to make room for it.
+    /// Returns false if the policy does not take the entry, the key then
+    /// keeps the entry it had.
+    pub fn insert(&mut self, k: K, v: &V) -> io::Result<bool> {
+        let n = self.next;
+        self.next += 1;
+        let mut body = vec![];
+        k.encode(&mut body);
+        v.encode(&mut body);
+        let mut buf = Vec::with_capacity(body.len() + 8);
+        snapshot::write_block(&mut buf, &body)?;
+        fs::write(self.path(n), buf)?;
+        self.index(k, n)
+    }
+
+    // Map a key to a new file, deleting its old file and the evicted one.
+    // Returns false if the policy does not take the entry.
+    fn index(&mut self, k: K, n: u64) -> io::Result<bool> {
+        let old = self.policy.peek(&k).cloned();
+        match self.policy.try_insert(k, n) {
+            Ok(Insertion::Inserted) => {}
+            Ok(Insertion::Evicted(_, evicted)) => self.delete(evicted)?,
+            Ok(Insertion::Rejected(k, _)) | Err(AllPinned(k, _)) => {
+                self.delete(n)?;
+                // The key keeps its old file, unless the policy dropped it
+                if let Some(old) = old {
+                    if self.policy.peek(&k) != Some(&old) {
+                        self.delete(old)?;
+                    }
+                }
+                return Ok(false);
+            }
+        }
+        if let Some(old) = old {
+            self.delete(old)?;
+        }
+        Ok(true)
+    }
+
+    /// Delete the entry of a key, returns true if it was on disk.
+    pub fn remove(&mut self, k: &K) -> io::Result<bool> {
+        match self.policy.remove(k) {
+            Some(n) => self.delete(n).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Read the value of a key and delete its entry.
+    pub fn take(&mut self, k: &K) -> io::Result<Option<V>> {
+        let v = self.get(k)?;
+        self.remove(k)?;
+        Ok(v)
+    }
+
+    /// Delete every entry.
+    pub fn clear(&mut self) -> io::Result<()> {
+        let mut files = vec![];
+        self.policy.clear();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with("entry.") {
+                files.push(entry.path());
+            }
+        }
+        files.iter().try_for_each(fs::remove_file)
+    }
+
+    fn path(&self, n: u64) -> PathBuf {
+        self.dir.join(format!("entry.{}", n))
+    }
+
+    fn read(&self, n: u64) -> io::Result<(K, V)> {
+        let buf = fs::read(self.path(n))?;
+        let body = snapshot::read_block(&mut &buf[..])?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        let mut body = &body[..];
+        let k = K::decode(&mut body)?;
+        let v = V::decode(&mut body)?;
+        Ok((k, v))
+    }
+
+    fn delete(&self, n: u64) -> io::Result<()> {
+        match fs::remove_file(self.path(n)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Errors of a torn or corrupted entry file
+fn is_damaged(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof | io::ErrorKind::NotFound
+    )
+}
+
+/// A two-level cache: a memory tier in front of a disk tier, each with its
+/// own policy and capacity.
+///
+/// An entry is in one tier at a time. Entries evicted from memory are
+/// demoted to disk, and a hit on disk promotes the entry back to memory.
+/// An entry the memory tier does not take, because its admission policy
+/// rejects it, stays on disk or goes there.
+/// If demoting an entry fails, the entry is lost and the error returned.
+pub struct Tiered<K, V, C1, C2> {
+    memory: C1,
+    disk: DiskTier<K, V, C2>,
+    _marker: PhantomData<V>,
+}
+
+impl<K, V, C1, C2> Tiered<K, V, C1, C2>
+where
+    K: Eq + Hash + Clone + Codec,
+    V: Codec,
+    C1: Cache<K, V>,
+    C2: Cache<K, u64>,
+{
+    /// Stack an empty memory tier on a disk tier.
+    pub fn new(memory: C1, disk: DiskTier<K, V, C2>) -> Tiered<K, V, C1, C2> {
+        Tiered {
+            memory,
+            disk,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the memory tier.
+    #[inline]
+    pub fn memory(&self) -> &C1 {
+        &self.memory
+    }
+
+    /// Returns the disk tier.
+    #[inline]
+    pub fn disk(&self) -> &DiskTier<K, V, C2> {
+        &self.disk
+    }
+
+    /// Returns the number of entries in both tiers.
+    pub fn len(&self) -> usize {
+        self.memory.len() + self.disk.len()
+    }
+
+    /// Returns true if both tiers are empty.
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_empty() && self.disk.is_empty()
+    }
+
+    /// Returns true if either tier holds the key.
+    pub fn contains(&self, k: &K) -> bool {
+        self.memory.contains(k) || self.disk.contains(k)
+    }
+
+    /// Get value with key, promoting it to memory if it is on disk.
+    /// An entry memory does not take stays on disk and is returned as
+    /// `Loaded::Uncached`.
+    pub fn get(&mut self, k: &K) -> io::Result<Option<Loaded<'_, V>>> {
+        if self.memory.contains(k) {
+            return Ok(self.memory.get(k).map(Loaded::Cached));
+        }
+        let v = match self.disk.get(k)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let (v, evicted) = self.memory.insert_loaded(k.clone(), v);
+        if let Loaded::Cached(_) = v {
+            self.disk.remove(k)?;
+        }
+        if let Some((evicted, ev)) = evicted {
+            self.disk.insert(evicted, &ev)?;
+        }
+        Ok(Some(v))
+    }
+
+    /// Insert K-V pair to memory, demoting the entry evicted to make room for it.
+    /// Returns false if neither tier takes the entry.
+    pub fn insert(&mut self, k: K, v: V) -> io::Result<bool> {
+        self.disk.remove(&k)?;
+        match self.memory.try_insert(k, v) {
+            Ok(Insertion::Inserted) => Ok(true),
+            Ok(Insertion::Evicted(k, v)) => self.disk.insert(k, &v).map(|_| true),
+            Ok(Insertion::Rejected(k, v)) | Err(AllPinned(k, v)) => self.disk.insert(k, &v),
+        }
+    }
+
+    /// Remove a key from both tiers, returns its value.
+    pub fn remove(&mut self, k: &K) -> io::Result<Option<V>> {
+        match self.memory.remove(k) {
+            Some(v) => Ok(Some(v)),
+            None => self.disk.take(k),
+        }
+    }
+
+    /// Remove every entry of both tiers.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.memory.clear();
+        self.disk.clear()
+    }
+}
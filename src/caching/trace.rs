@@ -0,0 +1,163 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::caching::sim::Access;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Lines};
+use std::path::Path;
+use std::str::FromStr;
+
+// Block traces count in 512-byte sectors
+const SECTOR: u64 = 512;
+
+/// Format of a trace file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    /// Block traces of the ARC and LIRS papers:
+    /// `start [count [ignored request_number]]` per line,
+    /// every line is expanded into `count` accesses of 512-byte blocks.
+    Arc,
+    /// SPC-1 / UMass financial traces: `ASU,LBA,Size,Opcode,Timestamp`,
+    /// the key is the ASU in the high 16 bits and the LBA in the low 48 bits.
+    Spc,
+    /// MSR Cambridge traces:
+    /// `Timestamp,Hostname,DiskNumber,Type,Offset,Size,ResponseTime`,
+    /// the key is the disk in the high 16 bits and the sector in the low 48 bits.
+    Msr,
+    /// `key[,size]` per line, non-numeric keys are hashed.
+    Simple,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "arc" | "lirs" => Ok(Format::Arc),
+            "spc" | "umass" => Ok(Format::Spc),
+            "msr" => Ok(Format::Msr),
+            "simple" => Ok(Format::Simple),
+            _ => Err(format!("unknown trace format: {}", s)),
+        }
+    }
+}
+
+/// Streams the accesses of a trace, one line at a time.
+/// Empty lines and lines starting with `#` are skipped.
+pub struct TraceReader<R> {
+    lines: Lines<R>,
+    format: Format,
+    line_no: usize,
+    // Remaining blocks of the current ARC line
+    pending: Option<(u64, u64)>,
+}
+
+/// Open a trace file.
+pub fn open<P: AsRef<Path>>(path: P, format: Format) -> io::Result<TraceReader<BufReader<File>>> {
+    Ok(TraceReader::new(BufReader::new(File::open(path)?), format))
+}
+
+impl<R: BufRead> TraceReader<R> {
+    /// Create a reader of the given format.
+    pub fn new(reader: R, format: Format) -> TraceReader<R> {
+        TraceReader {
+            lines: reader.lines(),
+            format,
+            line_no: 0,
+            pending: None,
+        }
+    }
+
+    fn invalid(&self, what: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line {}: {}", self.line_no, what),
+        )
+    }
+
+    fn number(&self, field: Option<&str>, name: &str) -> io::Result<u64> {
+        let field = field.ok_or_else(|| self.invalid(&format!("missing {}", name)))?;
+        field
+            .trim()
+            .parse()
+            .map_err(|_| self.invalid(&format!("invalid {}: {}", name, field)))
+    }
+
+    fn parse(&mut self, line: &str) -> io::Result<Access<u64>> {
+        match self.format {
+            Format::Arc => {
+                let mut fields = line.split_whitespace();
+                let start = self.number(fields.next(), "start block")?;
+                let count = match fields.next() {
+                    Some(c) => self.number(Some(c), "block count")?,
+                    None => 1,
+                };
+                if count > 1 {
+                    self.pending = Some((start + 1, start + count));
+                }
+                Ok(Access::with_size(start, SECTOR))
+            }
+            Format::Spc => {
+                let mut fields = line.split(',');
+                let asu = self.number(fields.next(), "ASU")?;
+                let lba = self.number(fields.next(), "LBA")?;
+                let size = self.number(fields.next(), "size")?;
+                Ok(Access::with_size(volume_key(asu, lba), size))
+            }
+            Format::Msr => {
+                let mut fields = line.split(',').skip(2);
+                let disk = self.number(fields.next(), "disk number")?;
+                let offset = self.number(fields.nth(1), "offset")?;
+                let size = self.number(fields.next(), "size")?;
+                Ok(Access::with_size(volume_key(disk, offset / SECTOR), size))
+            }
+            Format::Simple => {
+                let mut fields = line.splitn(2, ',');
+                let key = fields.next().unwrap().trim();
+                let key = key.parse().unwrap_or_else(|_| {
+                    let mut h = DefaultHasher::new();
+                    key.hash(&mut h);
+                    h.finish()
+                });
+                let size = match fields.next() {
+                    Some(s) => self.number(Some(s), "size")?,
+                    None => 1,
+                };
+                Ok(Access::with_size(key, size))
+            }
+        }
+    }
+}
+
+#[inline]
+fn volume_key(volume: u64, block: u64) -> u64 {
+    (volume << 48) | (block & ((1 << 48) - 1))
+}
+
+impl<R: BufRead> Iterator for TraceReader<R> {
+    type Item = io::Result<Access<u64>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((next, end)) = self.pending {
+            self.pending = if next + 1 < end {
+                Some((next + 1, end))
+            } else {
+                None
+            };
+            return Some(Ok(Access::with_size(next, SECTOR)));
+        }
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            };
+            self.line_no += 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            return Some(self.parse(line));
+        }
+    }
+}
@@ -1,5 +1,8 @@
 #![allow(dead_code)]
 #![deny(missing_docs)]
+use crate::caching::admission::AdmissionPolicy;
+use crate::caching::shards::{self, SpatialSampler};
+use crate::caching::{AllPinned, Cache, Insertion};
 use crate::common::list::{List, NodePtr};
 use std::collections::HashMap;
 use std::hash::Hash;
@@ -14,6 +17,8 @@ enum KeyPosition {
 // Value contains data and extra info for 2Q
 struct Value<K, V> {
     pos: KeyPosition,
+    // A pinned node is in neither queue
+    pinned: bool,
     node: NodePtr<Rc<K>>,
     data: V,
 }
@@ -47,14 +52,121 @@ impl<K, V> Value<K, V> {
 ///      end if
 ///      put p on the front of the A1 queue
 /// end if
+///
+/// Pinned entries are never evicted, they leave their queue until unpinned.
+/// With an admission policy, a new entry is only inserted in a full cache
+/// if the policy prefers it to the entry 2Q would evict.
 pub struct SimplifiedTwoQ<K: Eq + Hash, V> {
+    // SAFETY: a key is one Rc shared by its node in A1 or Am and its entry,
+    // and no clone of it may leave the cache: for_each_entry and victim lend
+    // &K, restore pushes the Rc it creates, and evict unwraps the key after
+    // dropping the node. Only the owning thread touches the counts, which
+    // the Send impl relies on.
     lru: List<Rc<K>>,
     fifo: List<Rc<K>>,
     fifo_cap: usize,
     cap: usize,
     entries: HashMap<Rc<K>, Value<K, V>>,
+    pinned: usize,
+    shadows: Option<Box<Shadows>>,
+    admission: Option<Box<dyn AdmissionPolicy<K> + Send>>,
 }
 
+/// Settings of the online tuning of the A1 threshold.
+/// A sample of the keys is replayed against tiny shadow caches, one per
+/// candidate threshold, and the real threshold periodically moves halfway
+/// toward the candidate with the most hits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Adaptive {
+    /// Candidate A1 thresholds, as ratios of the capacity.
+    pub ratios: Vec<f64>,
+    /// Rate at which keys are sampled into the shadow caches.
+    /// It is raised if the shadow caches would be smaller than 16 entries.
+    pub sample_rate: f64,
+    /// Number of sampled accesses between two adjustments.
+    pub interval: u64,
+}
+
+impl Default for Adaptive {
+    fn default() -> Self {
+        Adaptive {
+            ratios: vec![0.05, 0.1, 0.25, 0.4, 0.6],
+            sample_rate: 0.05,
+            interval: 1000,
+        }
+    }
+}
+
+// Shadow caches are keyed by the hash of the real keys
+struct Shadows {
+    sampler: SpatialSampler,
+    caches: Vec<(f64, SimplifiedTwoQ<u64, ()>, u64)>,
+    interval: u64,
+    accesses: u64,
+}
+
+const MIN_SHADOW_CAP: usize = 16;
+
+impl Shadows {
+    fn new(cap: usize, adaptive: &Adaptive) -> Shadows {
+        let min_rate = MIN_SHADOW_CAP as f64 / cap.max(1) as f64;
+        let rate = adaptive.sample_rate.max(min_rate).min(1.0);
+        let shadow_cap = ((cap as f64 * rate).ceil() as usize).max(1);
+        let caches = adaptive
+            .ratios
+            .iter()
+            .map(|&r| {
+                let threshold = ((shadow_cap as f64 * r).round() as usize).max(1);
+                (r, SimplifiedTwoQ::with_threshold(shadow_cap, threshold), 0)
+            })
+            .collect();
+        Shadows {
+            sampler: SpatialSampler::new(rate),
+            caches,
+            interval: adaptive.interval.max(1),
+            accesses: 0,
+        }
+    }
+
+    // Replay an access, returns the best ratio at the end of an interval
+    fn access<K: Hash>(&mut self, k: &K, count: bool) -> Option<f64> {
+        let h = shards::hash(k);
+        if !self.sampler.sample_hash(h) {
+            return None;
+        }
+        for (_, cache, hits) in self.caches.iter_mut() {
+            if cache.contains(&h) {
+                if count {
+                    cache.get(&h);
+                    *hits += 1;
+                }
+            } else {
+                cache.insert(h, ());
+            }
+        }
+        if !count {
+            return None;
+        }
+        self.accesses += 1;
+        if !self.accesses.is_multiple_of(self.interval) {
+            return None;
+        }
+        let mut best = self.caches.first()?;
+        for c in self.caches.iter() {
+            if c.2 > best.2 {
+                best = c;
+            }
+        }
+        let ratio = best.0;
+        // Older intervals weigh less and less
+        self.caches.iter_mut().for_each(|c| c.2 /= 2);
+        Some(ratio)
+    }
+}
+
+// SAFETY: see lru, the keys and the nodes move with the cache.
+unsafe impl<K: Eq + Hash + Send, V: Send> Send for SimplifiedTwoQ<K, V> {}
+
 impl<K: Eq + Hash, V> Drop for SimplifiedTwoQ<K, V> {
     fn drop(&mut self) {
         self.clear();
@@ -76,33 +188,175 @@ impl<K: Eq + Hash, V> SimplifiedTwoQ<K, V> {
             fifo_cap: a1_threshold,
             cap,
             entries: HashMap::new(),
+            pinned: 0,
+            shadows: None,
+            admission: None,
         }
     }
 
+    /// Consult an admission policy before evicting an entry for a new one.
+    /// The policy is not part of snapshots.
+    pub fn with_admission<A>(mut self, admission: A) -> SimplifiedTwoQ<K, V>
+    where
+        A: AdmissionPolicy<K> + Send + 'static,
+    {
+        self.admission = Some(Box::new(admission));
+        self
+    }
+
+    /// Create a new simplified 2Q with capacity, starting with an A1
+    /// threshold of cap/3 which is then tuned online.
+    pub fn with_adaptive(cap: usize, adaptive: Adaptive) -> SimplifiedTwoQ<K, V> {
+        let mut q = Self::with_capacity(cap);
+        q.shadows = Some(Box::new(Shadows::new(cap, &adaptive)));
+        q
+    }
+
+    /// Returns the current A1 threshold.
+    #[inline]
+    pub fn threshold(&self) -> usize {
+        self.fifo_cap
+    }
+
+    fn shadow_access(&mut self, k: &K, count: bool) {
+        let best = match self.shadows.as_mut() {
+            Some(s) => s.access(k, count),
+            None => return,
+        };
+        if let Some(ratio) = best {
+            let target = ((self.cap as f64 * ratio).round() as usize).max(1);
+            self.fifo_cap = if self.fifo_cap.max(target) - self.fifo_cap.min(target) <= 1 {
+                target
+            } else {
+                (self.fifo_cap + target) / 2
+            };
+        }
+    }
+
+    /// Returns the number of elements the cache can hold.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// Returns the number of elements in the cache.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if the cache contains no elements.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get value with key, without counting it as an access.
+    pub fn peek(&self, k: &K) -> Option<&V> {
+        self.entries.get(k).map(|v| &v.data)
+    }
+
+    /// Returns true if the key is cached, without counting it as an access.
+    pub fn contains(&self, k: &K) -> bool {
+        self.entries.contains_key(k)
+    }
+
+    /// Remove an entry from the cache, returns its value.
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        let v = self.entries.remove(k)?;
+        unsafe {
+            if v.pinned {
+                self.pinned -= 1;
+            } else if v.at_am() {
+                self.lru.remove_node(v.node);
+            } else {
+                self.fifo.remove_node(v.node);
+            }
+            drop(Box::from_raw(v.node.unwrap().as_ptr()));
+        }
+        Some(v.data)
+    }
+
     /// Remove all data in the cache.
     pub fn clear(&mut self) {
         while self.lru.pop_back().is_some() {}
         while self.fifo.pop_back().is_some() {}
         for v in self.entries.values_mut() {
             unsafe {
-                std::ptr::drop_in_place(v.node.unwrap().as_ptr());
+                drop(Box::from_raw(v.node.unwrap().as_ptr()));
             }
         }
         self.entries.clear();
+        self.pinned = 0;
+    }
+
+    /// Pin an entry so that it is never evicted,
+    /// returns false if the key is not cached.
+    pub fn pin(&mut self, k: &K) -> bool {
+        let v = match self.entries.get_mut(k) {
+            Some(v) => v,
+            None => return false,
+        };
+        if !v.pinned {
+            unsafe {
+                if v.at_am() {
+                    self.lru.remove_node(v.node);
+                } else {
+                    self.fifo.remove_node(v.node);
+                }
+            }
+            v.pinned = true;
+            self.pinned += 1;
+        }
+        true
+    }
+
+    /// Unpin an entry, putting it on the front of its queue,
+    /// returns false if the key is not pinned.
+    pub fn unpin(&mut self, k: &K) -> bool {
+        let v = match self.entries.get_mut(k) {
+            Some(v) if v.pinned => v,
+            _ => return false,
+        };
+        if v.at_am() {
+            self.lru.push_front_node(v.node);
+        } else {
+            self.fifo.push_front_node(v.node);
+        }
+        v.pinned = false;
+        self.pinned -= 1;
+        true
+    }
+
+    /// Returns true if the key is pinned.
+    pub fn is_pinned(&self, k: &K) -> bool {
+        self.entries.get(k).is_some_and(|v| v.pinned)
+    }
+
+    /// Returns the number of pinned entries.
+    #[inline]
+    pub fn pinned_len(&self) -> usize {
+        self.pinned
     }
 
     /// Get value with key.
     pub fn get(&mut self, k: &K) -> Option<&V> {
+        self.shadow_access(k, true);
         if !self.entries.contains_key(k) {
             return None;
         }
+        if let Some(admission) = self.admission.as_mut() {
+            admission.record(k);
+        }
         self.update(k);
         self.entries.get(k).map(|v| &v.data)
     }
 
     fn update(&mut self, k: &K) {
         let v = self.entries.get_mut(k).unwrap();
-        if v.at_am() {
+        if v.pinned {
+            v.pos = KeyPosition::Am;
+        } else if v.at_am() {
             unsafe {
                 self.lru.remove_node(v.node);
                 self.lru.push_front_node(v.node);
@@ -116,37 +370,244 @@ impl<K: Eq + Hash, V> SimplifiedTwoQ<K, V> {
         }
     }
 
-    /// Insert K-V pair to the cache.
-    pub fn insert(&mut self, k: K, v: V) {
-        let k = Rc::new(k);
+    /// Insert K-V pair to the cache,
+    /// returns the entry evicted to make room for it, if any.
+    /// A new entry the admission policy rejects, or that finds the cache
+    /// full of pinned entries, is dropped. Use `try_insert` to get it back
+    /// when entries are pinned.
+    pub fn insert(&mut self, k: K, v: V) -> Option<(K, V)> {
+        self.try_insert(k, v).ok().and_then(Insertion::evicted)
+    }
+
+    /// Insert K-V pair to the cache,
+    /// returns the entry evicted to make room for it, if any,
+    /// or the new entry if it was rejected,
+    /// or an error if the cache is full and every entry is pinned.
+    pub fn try_insert(&mut self, k: K, v: V) -> Result<Insertion<K, V>, AllPinned<K, V>> {
+        if let Some(admission) = self.admission.as_mut() {
+            admission.record(&k);
+        }
         if let Some(entry) = self.entries.get_mut(&k) {
             entry.data = v;
             self.update(&k);
+            return Ok(Insertion::Inserted);
+        }
+        self.shadow_access(&k, false);
+        if self.cap == 0 {
+            return Ok(Insertion::Rejected(k, v));
+        }
+        // Eviction
+        let evicted = if self.entries.len() < self.cap {
+            None
+        } else if self.pinned == self.entries.len() {
+            return Err(AllPinned(k, v));
+        } else if !self.admit(&k) {
+            return Ok(Insertion::Rejected(k, v));
         } else {
-            // Eviction
-            if self.entries.len() < self.cap {
-            } else if self.fifo.len >= self.fifo_cap {
-                let p = self.fifo.pop_back();
+            self.evict()
+        };
+        let k = Rc::new(k);
+        self.fifo.push_front(k.clone());
+        self.entries.insert(
+            k,
+            Value {
+                pos: KeyPosition::A1,
+                data: v,
+                pinned: false,
+                node: self.fifo.head,
+            },
+        );
+        Ok(match evicted {
+            Some((k, v)) => Insertion::Evicted(k, v),
+            None => Insertion::Inserted,
+        })
+    }
+
+    fn admit(&mut self, k: &K) -> bool {
+        let mut admission = match self.admission.take() {
+            Some(admission) => admission,
+            None => return true,
+        };
+        let admitted = admission.admit(k, self.victim().unwrap());
+        self.admission = Some(admission);
+        admitted
+    }
+
+    fn evict(&mut self) -> Option<(K, V)> {
+        let p = if (self.fifo.len >= self.fifo_cap && self.fifo.len > 0) || self.lru.len == 0 {
+            self.fifo.pop_back()
+        } else {
+            self.lru.pop_back()
+        };
+        let node = unsafe { Box::from_raw(p?.as_ptr()) };
+        let (k, v) = self.entries.remove_entry(&node.value).unwrap();
+        drop(node);
+        Rc::try_unwrap(k).ok().map(|k| (k, v.data))
+    }
+}
+
+impl<K: Eq + Hash, V> SimplifiedTwoQ<K, V> {
+    // Visit every entry with its queue, A1 then Am, from tail to head,
+    // then the pinned ones
+    pub(crate) fn for_each_entry<'a, F: FnMut(&'a K, &'a V, bool)>(&'a self, mut f: F) {
+        for list in [&self.fifo, &self.lru].iter() {
+            let mut cur = list.tail;
+            while let Some(n) = cur {
                 unsafe {
-                    self.entries.remove(&p.unwrap().as_ref().value);
-                    std::ptr::drop_in_place(p.unwrap().as_ptr());
+                    let k = &n.as_ref().value;
+                    let v = &self.entries[k];
+                    f(k, &v.data, v.at_am());
+                    cur = n.as_ref().prev;
                 }
-            } else {
-                let p = self.lru.pop_back();
-                unsafe {
-                    self.entries.remove(&p.unwrap().as_ref().value);
-                    std::ptr::drop_in_place(p.unwrap().as_ptr());
+            }
+        }
+        for (k, v) in self.entries.iter().filter(|(_, v)| v.pinned) {
+            f(k, &v.data, v.at_am());
+        }
+    }
+
+    // Returns the key evict would pick, None if every entry is pinned
+    pub(crate) fn victim(&self) -> Option<&K> {
+        let a1_first = (self.fifo.len >= self.fifo_cap && self.fifo.len > 0) || self.lru.len == 0;
+        let tail = if a1_first {
+            self.fifo.tail
+        } else {
+            self.lru.tail
+        };
+        tail.map(|n| unsafe { &*n.as_ref().value })
+    }
+
+    // Put an entry on the front of its queue.
+    // Entries must be restored from tail to head and the cache must not be full.
+    pub(crate) fn restore(&mut self, k: K, v: V, at_am: bool) {
+        if self.entries.len() >= self.cap || self.entries.contains_key(&k) {
+            return;
+        }
+        let k = Rc::new(k);
+        let (list, pos) = if at_am {
+            (&mut self.lru, KeyPosition::Am)
+        } else {
+            (&mut self.fifo, KeyPosition::A1)
+        };
+        list.push_front(k.clone());
+        let node = list.head;
+        self.entries.insert(
+            k,
+            Value {
+                pos,
+                pinned: false,
+                node,
+                data: v,
+            },
+        );
+    }
+
+    // True if the shadows or the admission policy hold state a snapshot does not
+    pub(crate) fn has_learned_state(&self) -> bool {
+        self.shadows.is_some() || self.admission.is_some()
+    }
+}
+
+impl<K: Eq + Hash, V> Cache<K, V> for SimplifiedTwoQ<K, V> {
+    fn get(&mut self, k: &K) -> Option<&V> {
+        SimplifiedTwoQ::get(self, k)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        SimplifiedTwoQ::peek(self, k)
+    }
+
+    fn insert(&mut self, k: K, v: V) -> Option<(K, V)> {
+        SimplifiedTwoQ::insert(self, k, v)
+    }
+
+    fn try_insert(&mut self, k: K, v: V) -> Result<Insertion<K, V>, AllPinned<K, V>> {
+        SimplifiedTwoQ::try_insert(self, k, v)
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        SimplifiedTwoQ::remove(self, k)
+    }
+
+    fn contains(&self, k: &K) -> bool {
+        SimplifiedTwoQ::contains(self, k)
+    }
+
+    fn len(&self) -> usize {
+        SimplifiedTwoQ::len(self)
+    }
+
+    fn capacity(&self) -> usize {
+        SimplifiedTwoQ::capacity(self)
+    }
+
+    fn clear(&mut self) {
+        SimplifiedTwoQ::clear(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use super::SimplifiedTwoQ;
+    use serde::de::{Deserialize, Deserializer};
+    use serde::ser::{Serialize, Serializer};
+    use std::hash::Hash;
+
+    #[derive(serde::Serialize)]
+    #[serde(rename = "SimplifiedTwoQ")]
+    struct StateRef<'a, K, V> {
+        cap: usize,
+        a1_threshold: usize,
+        a1: Vec<(&'a K, &'a V)>,
+        am: Vec<(&'a K, &'a V)>,
+    }
+
+    #[derive(serde::Deserialize)]
+    #[serde(rename = "SimplifiedTwoQ")]
+    struct State<K, V> {
+        cap: usize,
+        a1_threshold: usize,
+        a1: Vec<(K, V)>,
+        am: Vec<(K, V)>,
+    }
+
+    /// A1 and Am are serialized from tail to head.
+    /// The shadow caches of an adaptive 2Q are not, only its current threshold.
+    /// Pinned entries come last in their queue and are deserialized unpinned.
+    impl<K: Eq + Hash + Serialize, V: Serialize> Serialize for SimplifiedTwoQ<K, V> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut state = StateRef {
+                cap: self.cap,
+                a1_threshold: self.fifo_cap,
+                a1: Vec::with_capacity(self.fifo.len),
+                am: Vec::with_capacity(self.lru.len),
+            };
+            self.for_each_entry(|k, v, at_am| {
+                if at_am {
+                    state.am.push((k, v));
+                } else {
+                    state.a1.push((k, v));
                 }
+            });
+            state.serialize(serializer)
+        }
+    }
+
+    impl<'de, K, V> Deserialize<'de> for SimplifiedTwoQ<K, V>
+    where
+        K: Eq + Hash + Deserialize<'de>,
+        V: Deserialize<'de>,
+    {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let state = State::<K, V>::deserialize(deserializer)?;
+            let mut cache = SimplifiedTwoQ::with_threshold(state.cap, state.a1_threshold);
+            for (k, v) in state.a1 {
+                cache.restore(k, v, false);
+            }
+            for (k, v) in state.am {
+                cache.restore(k, v, true);
             }
-            self.fifo.push_front(k.clone());
-            self.entries.insert(
-                k.clone(),
-                Value {
-                    pos: KeyPosition::A1,
-                    data: v,
-                    node: self.fifo.head,
-                },
-            );
+            Ok(cache)
         }
     }
 }
@@ -0,0 +1,452 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::caching::snapshot::{self, invalid, read_block, write_block, Codec, Snapshot};
+use crate::caching::{AllPinned, Cache, Insertion};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+// A log is a header followed by one block per record:
+//
+//   header:  magic "PWLL" | version u16
+//   block:   len u32 | tag u8 | payload | crc32 u32
+//
+// Records are appended in the order the operations were applied, so
+// replaying them on the state they started from rebuilds the same state.
+const MAGIC: &[u8; 4] = b"PWLL";
+const VERSION: u16 = 1;
+const HEADER_LEN: u64 = 6;
+
+const TAG_INSERT: u8 = 1;
+const TAG_REMOVE: u8 = 2;
+const TAG_ACCESS: u8 = 3;
+const TAG_CLEAR: u8 = 4;
+
+/// A mutation of a cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record<K, V> {
+    /// A K-V pair was inserted or updated.
+    Insert(K, V),
+    /// An entry was removed.
+    Remove(K),
+    /// A cached key was read with `get`.
+    Access(K),
+    /// The cache was cleared.
+    Clear,
+}
+
+impl<K, V> Record<K, V> {
+    /// Apply the mutation to a cache.
+    pub fn apply<C: Cache<K, V> + ?Sized>(self, cache: &mut C) {
+        match self {
+            Record::Insert(k, v) => {
+                cache.insert(k, v);
+            }
+            Record::Remove(k) => {
+                cache.remove(&k);
+            }
+            Record::Access(k) => {
+                cache.get(&k);
+            }
+            Record::Clear => cache.clear(),
+        }
+    }
+}
+
+impl<K: Codec, V: Codec> Record<K, V> {
+    fn decode(buf: &mut &[u8]) -> io::Result<Self> {
+        match u8::decode(buf)? {
+            TAG_INSERT => Ok(Record::Insert(K::decode(buf)?, V::decode(buf)?)),
+            TAG_REMOVE => Ok(Record::Remove(K::decode(buf)?)),
+            TAG_ACCESS => Ok(Record::Access(K::decode(buf)?)),
+            TAG_CLEAR => Ok(Record::Clear),
+            _ => Err(invalid("unknown log record")),
+        }
+    }
+}
+
+/// Appends records to a log.
+/// Records are written to w as they come, durability is up to the caller.
+pub struct LogWriter<W: Write> {
+    w: W,
+    body: Vec<u8>,
+}
+
+impl<W: Write> LogWriter<W> {
+    /// Write the header of a new log.
+    pub fn new(mut w: W) -> io::Result<LogWriter<W>> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        Ok(Self::append_to(w))
+    }
+
+    // Continue a log whose header is already written
+    fn append_to(w: W) -> LogWriter<W> {
+        LogWriter { w, body: vec![] }
+    }
+
+    /// Returns a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.w
+    }
+
+    /// Returns a mutable reference to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.w
+    }
+
+    /// Append an `Insert` record.
+    pub fn insert<K: Codec, V: Codec>(&mut self, k: &K, v: &V) -> io::Result<()> {
+        self.append(TAG_INSERT, |buf| {
+            k.encode(buf);
+            v.encode(buf);
+        })
+    }
+
+    /// Append a `Remove` record.
+    pub fn remove<K: Codec>(&mut self, k: &K) -> io::Result<()> {
+        self.append(TAG_REMOVE, |buf| k.encode(buf))
+    }
+
+    /// Append an `Access` record.
+    pub fn access<K: Codec>(&mut self, k: &K) -> io::Result<()> {
+        self.append(TAG_ACCESS, |buf| k.encode(buf))
+    }
+
+    /// Append a `Clear` record.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.append(TAG_CLEAR, |_| {})
+    }
+
+    fn append<F: FnOnce(&mut Vec<u8>)>(&mut self, tag: u8, f: F) -> io::Result<()> {
+        self.body.clear();
+        self.body.push(tag);
+        f(&mut self.body);
+        write_block(&mut self.w, &self.body)
+    }
+}
+
+// Read the records of a log until its end or its first damaged record.
+// Returns the number of records and the length of the intact prefix.
+fn read_log<K, V, R, F>(mut r: R, mut f: F) -> io::Result<(u64, u64)>
+where
+    K: Codec,
+    V: Codec,
+    R: Read,
+    F: FnMut(Record<K, V>),
+{
+    let mut header = [0; HEADER_LEN as usize];
+    match r.read_exact(&mut header) {
+        // Torn while the header was written
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok((0, 0)),
+        res => res?,
+    }
+    if &header[..4] != MAGIC {
+        return Err(invalid("not a log"));
+    }
+    if u16::from_le_bytes([header[4], header[5]]) != VERSION {
+        return Err(invalid("unsupported log version"));
+    }
+    let (mut records, mut len) = (0, HEADER_LEN);
+    loop {
+        let body = match read_block(&mut r) {
+            Ok(Some(body)) => body,
+            Ok(None) => break,
+            // Torn or damaged record, the rest of the log is lost
+            Err(e)
+                if e.kind() == io::ErrorKind::UnexpectedEof
+                    || e.kind() == io::ErrorKind::InvalidData =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        };
+        let mut buf = &body[..];
+        f(Record::decode(&mut buf)?);
+        records += 1;
+        len += body.len() as u64 + 8;
+    }
+    Ok((records, len))
+}
+
+/// Apply the records of a log to a cache, returns the number of records applied.
+/// A torn or damaged record ends the log: it and the records after it are ignored.
+pub fn replay<K, V, C, R>(cache: &mut C, r: R) -> io::Result<u64>
+where
+    K: Codec,
+    V: Codec,
+    C: Cache<K, V> + ?Sized,
+    R: Read,
+{
+    read_log(r, |record: Record<K, V>| record.apply(cache)).map(|(records, _)| records)
+}
+
+/// A cache whose mutations are logged in a directory, so it can be rebuilt
+/// exactly after a restart, from the last snapshot and the log written since.
+///
+/// The directory holds `snapshot.N` and `log.N`, where the log of generation N
+/// applies to the snapshot of generation N. A checkpoint writes generation N+1
+/// before deleting generation N, so a crash at any point leaves a usable pair.
+///
+/// Records are buffered: call `sync` to make them durable. Write errors are
+/// kept and returned by the next `sync`, a successful `checkpoint` clears them.
+pub struct Journaled<K, V, C> {
+    cache: C,
+    dir: PathBuf,
+    generation: u64,
+    log: LogWriter<BufWriter<File>>,
+    // Records in the current log
+    records: u64,
+    // Checkpoint before the next record once the log is this long
+    compact_every: Option<u64>,
+    // The first error, later records are skipped
+    err: Option<io::Error>,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K, V, C> Journaled<K, V, C>
+where
+    K: Codec,
+    V: Codec,
+    C: Cache<K, V> + Snapshot,
+{
+    /// Open the journal in dir, creating it if needed.
+    /// The cache is rebuilt from the last snapshot and its log,
+    /// init is only used when there is no snapshot yet.
+    ///
+    /// A cache whose snapshot is incomplete, for example one with an
+    /// admission policy, could not be rebuilt as it was and is refused
+    /// with an `InvalidInput` error.
+    pub fn open<P: AsRef<Path>>(dir: P, init: C) -> io::Result<Journaled<K, V, C>> {
+        if !init.is_snapshot_complete() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the cache holds state its snapshot does not",
+            ));
+        }
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut generation = None;
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            if let Some(g) = parse_generation(&name.to_string_lossy(), "snapshot.") {
+                generation = generation.max(Some(g));
+            }
+        }
+        let mut cache = match generation {
+            Some(g) => snapshot::load(dir.join(format!("snapshot.{}", g)))?,
+            None => init,
+        };
+        let generation = generation.unwrap_or(0);
+
+        let log_path = dir.join(format!("log.{}", generation));
+        let (records, len) = match File::open(&log_path) {
+            Ok(file) => read_log(BufReader::new(file), |record: Record<K, V>| {
+                record.apply(&mut cache)
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (0, 0),
+            Err(e) => return Err(e),
+        };
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&log_path)?;
+        // Drop the torn tail, so new records follow the intact ones
+        file.set_len(len)?;
+        file.seek(SeekFrom::End(0))?;
+        let log = if len == 0 {
+            LogWriter::new(BufWriter::new(file))?
+        } else {
+            LogWriter::append_to(BufWriter::new(file))
+        };
+
+        let journal = Journaled {
+            cache,
+            dir,
+            generation,
+            log,
+            records,
+            compact_every: None,
+            err: None,
+            _marker: PhantomData,
+        };
+        journal.remove_stale()?;
+        Ok(journal)
+    }
+
+    /// Write a checkpoint whenever the log reaches the given number of records.
+    pub fn compact_every(mut self, records: u64) -> Journaled<K, V, C> {
+        self.compact_every = Some(records.max(1));
+        self
+    }
+
+    /// Returns the wrapped cache.
+    #[inline]
+    pub fn cache(&self) -> &C {
+        &self.cache
+    }
+
+    /// Returns the number of records logged since the last checkpoint.
+    #[inline]
+    pub fn log_len(&self) -> u64 {
+        self.records
+    }
+
+    /// Flush the log and sync it to disk.
+    /// Returns the first error met while logging since the last sync.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if let Some(e) = self.err.take() {
+            return Err(e);
+        }
+        self.log.get_mut().flush()?;
+        self.log.get_ref().get_ref().sync_data()
+    }
+
+    /// Write a snapshot of the cache as a new generation with an empty log,
+    /// then delete the previous generation.
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        let next = self.generation + 1;
+        snapshot::save(&self.cache, self.dir.join(format!("snapshot.{}", next)))?;
+        let file = File::create(self.dir.join(format!("log.{}", next)))?;
+        let mut log = LogWriter::new(BufWriter::new(file))?;
+        log.get_mut().flush()?;
+        log.get_ref().get_ref().sync_data()?;
+        self.log = log;
+        self.generation = next;
+        self.records = 0;
+        // The snapshot holds everything the failed records missed
+        self.err = None;
+        self.remove_stale()
+    }
+
+    // Delete the files of other generations and unfinished snapshots
+    fn remove_stale(&self) -> io::Result<()> {
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            let stale = ["snapshot.", "log."].iter().any(|prefix| {
+                name.starts_with(prefix) && parse_generation(&name, prefix) != Some(self.generation)
+            });
+            if stale {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn log<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut LogWriter<BufWriter<File>>) -> io::Result<()>,
+    {
+        if self.err.is_some() {
+            return;
+        }
+        match f(&mut self.log) {
+            Ok(()) => self.records += 1,
+            Err(e) => self.err = Some(e),
+        }
+    }
+
+    fn maybe_compact(&mut self) {
+        match self.compact_every {
+            Some(n) if self.records >= n => {
+                if let Err(e) = self.checkpoint() {
+                    self.err.get_or_insert(e);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Get value with key, logging the access if it is cached.
+    pub fn get(&mut self, k: &K) -> Option<&V> {
+        if self.cache.contains(k) {
+            self.maybe_compact();
+            self.log(|log| log.access(k));
+        }
+        self.cache.get(k)
+    }
+
+    /// Insert K-V pair to the cache,
+    /// returns the entry evicted to make room for it, if any.
+    pub fn insert(&mut self, k: K, v: V) -> Option<(K, V)> {
+        self.maybe_compact();
+        self.log(|log| log.insert(&k, &v));
+        self.cache.insert(k, v)
+    }
+
+    /// Insert K-V pair to the cache like `Cache::try_insert`.
+    pub fn try_insert(&mut self, k: K, v: V) -> Result<Insertion<K, V>, AllPinned<K, V>> {
+        self.maybe_compact();
+        self.log(|log| log.insert(&k, &v));
+        self.cache.try_insert(k, v)
+    }
+
+    /// Remove an entry from the cache, returns its value.
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        if !self.cache.contains(k) {
+            return None;
+        }
+        self.maybe_compact();
+        self.log(|log| log.remove(k));
+        self.cache.remove(k)
+    }
+
+    /// Remove all data in the cache.
+    pub fn clear(&mut self) {
+        self.maybe_compact();
+        self.log(|log| log.clear());
+        self.cache.clear();
+    }
+}
+
+// Parse N in prefix + N, None for any other name
+fn parse_generation(name: &str, prefix: &str) -> Option<u64> {
+    name.strip_prefix(prefix)?.parse().ok()
+}
+
+impl<K, V, C> Cache<K, V> for Journaled<K, V, C>
+where
+    K: Codec,
+    V: Codec,
+    C: Cache<K, V> + Snapshot,
+{
+    fn get(&mut self, k: &K) -> Option<&V> {
+        Journaled::get(self, k)
+    }
+
+    fn peek(&self, k: &K) -> Option<&V> {
+        self.cache.peek(k)
+    }
+
+    fn insert(&mut self, k: K, v: V) -> Option<(K, V)> {
+        Journaled::insert(self, k, v)
+    }
+
+    fn try_insert(&mut self, k: K, v: V) -> Result<Insertion<K, V>, AllPinned<K, V>> {
+        Journaled::try_insert(self, k, v)
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        Journaled::remove(self, k)
+    }
+
+    fn contains(&self, k: &K) -> bool {
+        self.cache.contains(k)
+    }
+
+    fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.cache.capacity()
+    }
+
+    fn clear(&mut self) {
+        Journaled::clear(self)
+    }
+}
@@ -0,0 +1,230 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::caching::{AllPinned, Cache, Insertion, Loaded};
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::hash::Hash;
+
+/// Storage behind a write-back cache, such as a key-value store.
+pub trait BackingStore<K, V> {
+    /// Error of the store.
+    type Error;
+
+    /// Read the value of a key, None if the key is not stored.
+    fn read(&mut self, k: &K) -> Result<Option<V>, Self::Error>;
+
+    /// Store the value of a key.
+    fn write(&mut self, k: &K, v: &V) -> Result<(), Self::Error>;
+
+    /// Delete a key, deleting a missing key is not an error.
+    fn delete(&mut self, k: &K) -> Result<(), Self::Error>;
+}
+
+impl<K, V, S: BackingStore<K, V> + ?Sized> BackingStore<K, V> for &mut S {
+    type Error = S::Error;
+
+    fn read(&mut self, k: &K) -> Result<Option<V>, S::Error> {
+        (**self).read(k)
+    }
+
+    fn write(&mut self, k: &K, v: &V) -> Result<(), S::Error> {
+        (**self).write(k, v)
+    }
+
+    fn delete(&mut self, k: &K) -> Result<(), S::Error> {
+        (**self).delete(k)
+    }
+}
+
+/// An in-memory store.
+impl<K: Eq + Hash + Clone, V: Clone> BackingStore<K, V> for HashMap<K, V> {
+    type Error = Infallible;
+
+    fn read(&mut self, k: &K) -> Result<Option<V>, Infallible> {
+        Ok(self.get(k).cloned())
+    }
+
+    fn write(&mut self, k: &K, v: &V) -> Result<(), Infallible> {
+        self.insert(k.clone(), v.clone());
+        Ok(())
+    }
+
+    fn delete(&mut self, k: &K) -> Result<(), Infallible> {
+        self.remove(k);
+        Ok(())
+    }
+}
+
+/// A write-back cache in front of a backing store.
+///
+/// `put` only writes to the cache and marks the entry dirty. Dirty entries
+/// are written to the store when the policy evicts them, by `flush` and
+/// `flush_key`, and when the cache is dropped. Misses are read from the
+/// store, and `remove` deletes from the store right away. A value the cache
+/// does not take, because its admission policy rejects it, is returned by
+/// `get` as `Loaded::Uncached`, and written to the store right away by `put`.
+///
+/// If writing an evicted entry fails, the entry is kept aside, still
+/// readable, and written again by the next flush. The error is returned by
+/// the call that evicted it. The flush on drop tries every dirty value and its
+/// errors are lost, so call `flush` before dropping the cache to see them.
+pub struct WriteBack<K, V, C, S>
+where
+    K: Eq + Hash + Clone,
+    C: Cache<K, V>,
+    S: BackingStore<K, V>,
+{
+    cache: C,
+    store: S,
+    dirty: HashSet<K>,
+    // Evicted dirty entries that could not be written
+    unwritten: HashMap<K, V>,
+}
+
+impl<K, V, C, S> WriteBack<K, V, C, S>
+where
+    K: Eq + Hash + Clone,
+    C: Cache<K, V>,
+    S: BackingStore<K, V>,
+{
+    /// Create a write-back cache with an empty cache of any policy.
+    pub fn new(cache: C, store: S) -> WriteBack<K, V, C, S> {
+        WriteBack {
+            cache,
+            store,
+            dirty: HashSet::new(),
+            unwritten: HashMap::new(),
+        }
+    }
+
+    /// Returns the wrapped cache.
+    #[inline]
+    pub fn cache(&self) -> &C {
+        &self.cache
+    }
+
+    /// Returns the backing store.
+    #[inline]
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Returns a mutable reference to the backing store.
+    /// Writing to it directly bypasses the cache.
+    #[inline]
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    /// Returns true if the key has a value not written to the store yet.
+    pub fn is_dirty(&self, k: &K) -> bool {
+        self.dirty.contains(k) || self.unwritten.contains_key(k)
+    }
+
+    /// Returns the number of values not written to the store yet.
+    pub fn dirty_len(&self) -> usize {
+        self.dirty.len() + self.unwritten.len()
+    }
+
+    /// Get value with key, reading it from the store on a miss.
+    pub fn get(&mut self, k: &K) -> Result<Option<Loaded<'_, V>>, S::Error> {
+        if self.cache.contains(k) {
+            return Ok(self.cache.get(k).map(Loaded::Cached));
+        }
+        if self.unwritten.contains_key(k) {
+            return Ok(self.unwritten.get(k).map(Loaded::Cached));
+        }
+        let v = match self.store.read(k)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let (v, evicted) = self.cache.insert_loaded(k.clone(), v);
+        if let Some((k, ev)) = evicted {
+            write_evicted(&mut self.store, &mut self.dirty, &mut self.unwritten, k, ev)?;
+        }
+        Ok(Some(v))
+    }
+
+    /// Write a value to the cache, it is written to the store later.
+    pub fn put(&mut self, k: K, v: V) -> Result<(), S::Error> {
+        self.unwritten.remove(&k);
+        self.dirty.insert(k.clone());
+        match self.cache.try_insert(k, v) {
+            Ok(Insertion::Inserted) => Ok(()),
+            Ok(Insertion::Evicted(k, v)) | Ok(Insertion::Rejected(k, v)) | Err(AllPinned(k, v)) => {
+                write_evicted(&mut self.store, &mut self.dirty, &mut self.unwritten, k, v)
+            }
+        }
+    }
+
+    /// Remove a key from the cache and delete it from the store,
+    /// returns the value it had in the cache.
+    pub fn remove(&mut self, k: &K) -> Result<Option<V>, S::Error> {
+        self.store.delete(k)?;
+        self.dirty.remove(k);
+        let v = self.cache.remove(k);
+        Ok(v.or_else(|| self.unwritten.remove(k)))
+    }
+
+    /// Write a dirty value of a key to the store.
+    pub fn flush_key(&mut self, k: &K) -> Result<(), S::Error> {
+        if let Some(v) = self.unwritten.get(k) {
+            self.store.write(k, v)?;
+            self.unwritten.remove(k);
+        }
+        if self.dirty.contains(k) {
+            // A policy may have dropped the entry, there is nothing to write then
+            if let Some(v) = self.cache.peek(k) {
+                self.store.write(k, v)?;
+            }
+            self.dirty.remove(k);
+        }
+        Ok(())
+    }
+
+    /// Write all dirty values to the store, stopping at the first error.
+    pub fn flush(&mut self) -> Result<(), S::Error> {
+        self.dirty_keys().iter().try_for_each(|k| self.flush_key(k))
+    }
+
+    fn dirty_keys(&self) -> Vec<K> {
+        self.unwritten
+            .keys()
+            .chain(self.dirty.iter())
+            .cloned()
+            .collect()
+    }
+}
+
+// Write an entry that left the cache if it is dirty. It takes the fields
+// it needs, so get can call it while it holds the value it read.
+fn write_evicted<K: Eq + Hash, V, S: BackingStore<K, V>>(
+    store: &mut S,
+    dirty: &mut HashSet<K>,
+    unwritten: &mut HashMap<K, V>,
+    k: K,
+    v: V,
+) -> Result<(), S::Error> {
+    if !dirty.remove(&k) {
+        return Ok(());
+    }
+    if let Err(e) = store.write(&k, &v) {
+        unwritten.insert(k, v);
+        return Err(e);
+    }
+    Ok(())
+}
+
+impl<K, V, C, S> Drop for WriteBack<K, V, C, S>
+where
+    K: Eq + Hash + Clone,
+    C: Cache<K, V>,
+    S: BackingStore<K, V>,
+{
+    // Unlike flush, keep going past errors
+    fn drop(&mut self) {
+        for k in self.dirty_keys().iter() {
+            let _ = self.flush_key(k);
+        }
+    }
+}
@@ -1 +1,13 @@
+/// An Improved Data Stream Summary: The Count-Min Sketch and its Applications
+/// by Graham Cormode and S. Muthukrishnan
+/// [Paper](http://dimacs.rutgers.edu/~graham/pubs/papers/cm-full.pdf)
+pub mod sketch;
+
+/// Space/Time Trade-offs in Hash Coding with Allowable Errors
+/// by Burton H. Bloom
+/// [Paper](https://doi.org/10.1145/362686.362692)
+pub mod bloom;
+
+pub(crate) mod crc32;
+pub(crate) mod fenwick;
 pub(crate) mod list;
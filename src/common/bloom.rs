@@ -0,0 +1,220 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use crate::common::sketch::hash;
+use std::hash::Hash;
+
+#[test]
+fn test_sizing() {
+    let bloom = BloomFilter::new(1000, 0.01);
+    // 9.59 bits and 7 hashes per item
+    assert_eq!(bloom.bits(), 9586);
+    assert_eq!(bloom.hashes(), 7);
+    let bloom = BloomFilter::new(0, 0.5);
+    assert_eq!((bloom.bits(), bloom.hashes()), (64, 1));
+}
+
+// Number of bits and of hashes for items with a false positive rate
+fn sizing(items: usize, fp_rate: f64) -> (u64, u32) {
+    assert!(
+        fp_rate > 0.0 && fp_rate < 1.0,
+        "invalid false positive rate"
+    );
+    let items = items.max(1) as f64;
+    let ln2 = std::f64::consts::LN_2;
+    let bits = ((-items * fp_rate.ln() / (ln2 * ln2)).ceil() as u64).max(64);
+    let hashes = (-fp_rate.log2()).round().clamp(1.0, 16.0) as u32;
+    (bits, hashes)
+}
+
+// Positions of a hash, by double hashing
+#[inline]
+fn positions(h: u64, hashes: u32, len: u64) -> impl Iterator<Item = usize> {
+    let h2 = h.rotate_left(32) | 1;
+    (0..hashes as u64).map(move |i| (h.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+}
+
+/// A Bloom filter: a set of keys answering "maybe" or "no".
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    len_bits: u64,
+    hashes: u32,
+    seed: u64,
+    len: usize,
+}
+
+impl BloomFilter {
+    /// Create a filter whose false positive rate is fp_rate
+    /// once it holds the given number of items.
+    pub fn new(items: usize, fp_rate: f64) -> BloomFilter {
+        let (bits, hashes) = sizing(items, fp_rate);
+        BloomFilter {
+            bits: vec![0; bits.div_ceil(64) as usize],
+            len_bits: bits,
+            hashes,
+            seed: 0,
+            len: 0,
+        }
+    }
+
+    /// Hash the keys with a seed.
+    pub fn seed(mut self, seed: u64) -> BloomFilter {
+        self.seed = seed;
+        self
+    }
+
+    /// Returns the number of bits.
+    #[inline]
+    pub fn bits(&self) -> u64 {
+        self.len_bits
+    }
+
+    /// Returns the number of hashes per key.
+    #[inline]
+    pub fn hashes(&self) -> u32 {
+        self.hashes
+    }
+
+    /// Returns the number of keys inserted, not counting
+    /// the keys that were false positives when inserted.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if no key was inserted.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Insert a key, returns true if it was not in the filter.
+    pub fn insert<K: Hash + ?Sized>(&mut self, k: &K) -> bool {
+        let mut new = false;
+        for i in positions(hash(self.seed, k), self.hashes, self.len_bits) {
+            let (word, mask) = (i / 64, 1 << (i % 64));
+            new |= self.bits[word] & mask == 0;
+            self.bits[word] |= mask;
+        }
+        if new {
+            self.len += 1;
+        }
+        new
+    }
+
+    /// Returns true if the key may have been inserted,
+    /// false if it certainly was not.
+    pub fn contains<K: Hash + ?Sized>(&self, k: &K) -> bool {
+        positions(hash(self.seed, k), self.hashes, self.len_bits)
+            .all(|i| self.bits[i / 64] & (1 << (i % 64)) != 0)
+    }
+
+    /// Remove all keys.
+    pub fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|w| *w = 0);
+        self.len = 0;
+    }
+}
+
+/// A counting Bloom filter: a Bloom filter of counters, so keys can be removed.
+///
+/// Counters are 8 bits wide. A counter that reaches 255 stays there, so
+/// that removing keys never makes another key look absent.
+#[derive(Debug, Clone)]
+pub struct CountingBloomFilter {
+    counters: Vec<u8>,
+    hashes: u32,
+    seed: u64,
+    len: usize,
+}
+
+impl CountingBloomFilter {
+    /// Create a filter whose false positive rate is fp_rate
+    /// once it holds the given number of items.
+    pub fn new(items: usize, fp_rate: f64) -> CountingBloomFilter {
+        let (counters, hashes) = sizing(items, fp_rate);
+        CountingBloomFilter {
+            counters: vec![0; counters as usize],
+            hashes,
+            seed: 0,
+            len: 0,
+        }
+    }
+
+    /// Hash the keys with a seed.
+    pub fn seed(mut self, seed: u64) -> CountingBloomFilter {
+        self.seed = seed;
+        self
+    }
+
+    /// Returns the number of counters.
+    #[inline]
+    pub fn counters(&self) -> usize {
+        self.counters.len()
+    }
+
+    /// Returns the number of hashes per key.
+    #[inline]
+    pub fn hashes(&self) -> u32 {
+        self.hashes
+    }
+
+    /// Returns the number of keys inserted and not removed.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if no key is in the filter.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn positions<K: Hash + ?Sized>(&self, k: &K) -> impl Iterator<Item = usize> {
+        let len = self.counters.len() as u64;
+        positions(hash(self.seed, k), self.hashes, len)
+    }
+
+    /// Insert a key. A key can be inserted several times.
+    pub fn insert<K: Hash + ?Sized>(&mut self, k: &K) {
+        for i in self.positions(k).collect::<Vec<_>>() {
+            self.counters[i] = self.counters[i].saturating_add(1);
+        }
+        self.len += 1;
+    }
+
+    /// Remove a key inserted before, returns false if it is not in the filter.
+    /// Removing a key that was never inserted may remove another key.
+    /// A key whose counters all saturated stays in the filter for good.
+    pub fn remove<K: Hash + ?Sized>(&mut self, k: &K) -> bool {
+        if !self.contains(k) {
+            return false;
+        }
+        for i in self.positions(k).collect::<Vec<_>>() {
+            if self.counters[i] < u8::MAX {
+                self.counters[i] -= 1;
+            }
+        }
+        self.len = self.len.saturating_sub(1);
+        true
+    }
+
+    /// Returns true if the key may be in the filter,
+    /// false if it certainly is not.
+    pub fn contains<K: Hash + ?Sized>(&self, k: &K) -> bool {
+        self.positions(k).all(|i| self.counters[i] > 0)
+    }
+
+    /// Returns an upper bound of the number of times the key was inserted
+    /// and not removed, up to 255.
+    pub fn count<K: Hash + ?Sized>(&self, k: &K) -> u8 {
+        self.positions(k).map(|i| self.counters[i]).min().unwrap()
+    }
+
+    /// Remove all keys.
+    pub fn clear(&mut self) {
+        self.counters.iter_mut().for_each(|c| *c = 0);
+        self.len = 0;
+    }
+}
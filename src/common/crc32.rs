@@ -0,0 +1,60 @@
+#![allow(dead_code)]
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    let mut crc = Crc32::default();
+    crc.update(b"1234");
+    crc.update(b"56789");
+    assert_eq!(crc.finish(), 0xCBF4_3926);
+}
+
+// CRC-32 (IEEE 802.3), reflected polynomial
+const POLY: u32 = 0xEDB8_8320;
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { POLY ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+static TABLE: [u32; 256] = make_table();
+
+pub(crate) struct Crc32 {
+    crc: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32 { crc: !0 }
+    }
+}
+
+impl Crc32 {
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.crc = TABLE[((self.crc ^ b as u32) & 0xFF) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::default();
+    crc.update(bytes);
+    crc.finish()
+}
@@ -0,0 +1,59 @@
+#![allow(dead_code)]
+
+#[test]
+fn test_fenwick() {
+    let mut tree = Fenwick::new(10);
+    for i in 0..10 {
+        tree.add(i, i as i64);
+    }
+    assert_eq!(tree.len(), 10);
+    assert_eq!(tree.prefix(0), 0);
+    assert_eq!(tree.prefix(10), 45);
+    assert_eq!(tree.range(3, 6), 3 + 4 + 5);
+    tree.add(4, -4);
+    assert_eq!(tree.range(3, 6), 3 + 5);
+    assert_eq!(tree.range(6, 6), 0);
+}
+
+// Fenwick (binary indexed) tree over i64 counters
+pub(crate) struct Fenwick {
+    tree: Vec<i64>,
+}
+
+impl Fenwick {
+    pub(crate) fn new(len: usize) -> Fenwick {
+        Fenwick {
+            tree: vec![0; len + 1],
+        }
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.tree.len() - 1
+    }
+
+    // Add delta to the counter at i
+    pub(crate) fn add(&mut self, i: usize, delta: i64) {
+        let mut i = i + 1;
+        while i < self.tree.len() {
+            self.tree[i] += delta;
+            i += i & i.wrapping_neg();
+        }
+    }
+
+    // Sum of the counters in [0, end)
+    pub(crate) fn prefix(&self, end: usize) -> i64 {
+        let mut i = end;
+        let mut sum = 0;
+        while i > 0 {
+            sum += self.tree[i];
+            i -= i & i.wrapping_neg();
+        }
+        sum
+    }
+
+    // Sum of the counters in [start, end)
+    pub(crate) fn range(&self, start: usize, end: usize) -> i64 {
+        self.prefix(end) - self.prefix(start)
+    }
+}
@@ -99,6 +99,7 @@ impl<T> List<T> {
                 node.unwrap().as_mut().prev = self.tail;
                 self.tail = node;
             } else {
+                node.unwrap().as_mut().prev = None;
                 self.head = node;
                 self.tail = node;
             }
@@ -113,6 +114,7 @@ impl<T> List<T> {
                 node.unwrap().as_mut().next = self.head;
                 self.head = node;
             } else {
+                node.unwrap().as_mut().next = None;
                 self.head = node;
                 self.tail = node;
             }
@@ -0,0 +1,168 @@
+#![allow(dead_code)]
+#![deny(missing_docs)]
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+#[test]
+fn test_nibbles() {
+    let mut sketch = CountMinSketch::new(16, 1);
+    for i in 0..16u64 {
+        for _ in 0..i {
+            sketch.add(0, i as usize);
+        }
+    }
+    for i in 0..16 {
+        assert_eq!(sketch.get(0, i), (i as u8).min(MAX));
+    }
+    sketch.halve();
+    assert_eq!(sketch.get(0, 15), 7);
+    assert_eq!(sketch.get(0, 1), 0);
+}
+
+// Counters are 4 bits wide, 16 of them in a word
+const MAX: u8 = 15;
+const PER_WORD: usize = 16;
+
+/// Count-min sketch of the frequencies of keys, with 4-bit counters.
+///
+/// Increments are conservative: only the counters holding the current
+/// estimate of a key are incremented, which keeps the overestimation low.
+/// Counters saturate at 15, and can be halved every given number of
+/// increments so that old accesses fade out, as TinyLFU does.
+#[derive(Debug, Clone)]
+pub struct CountMinSketch {
+    // depth rows of width counters
+    table: Vec<u64>,
+    width: usize,
+    depth: usize,
+    seed: u64,
+    additions: u64,
+    halve_every: Option<u64>,
+}
+
+impl CountMinSketch {
+    /// Create a sketch of depth rows of width counters,
+    /// width is rounded up to a multiple of 16.
+    pub fn new(width: usize, depth: usize) -> CountMinSketch {
+        assert!(width > 0 && depth > 0, "CountMinSketch needs counters");
+        let width = width.div_ceil(PER_WORD) * PER_WORD;
+        CountMinSketch {
+            table: vec![0; depth * width / PER_WORD],
+            width,
+            depth,
+            seed: 0,
+            additions: 0,
+            halve_every: None,
+        }
+    }
+
+    /// Create a sketch overestimating a frequency by at most epsilon times
+    /// the number of increments, with probability 1 - delta.
+    /// Saturated counters do not follow these bounds.
+    pub fn with_error(epsilon: f64, delta: f64) -> CountMinSketch {
+        assert!(
+            epsilon > 0.0 && delta > 0.0 && delta < 1.0,
+            "invalid error target"
+        );
+        let width = (std::f64::consts::E / epsilon).ceil() as usize;
+        let depth = (1.0 / delta).ln().ceil().max(1.0) as usize;
+        Self::new(width, depth)
+    }
+
+    /// Hash the keys with a seed, so that sketches with different seeds
+    /// do not collide on the same keys.
+    pub fn seed(mut self, seed: u64) -> CountMinSketch {
+        self.seed = seed;
+        self
+    }
+
+    /// Halve all counters every given number of increments.
+    pub fn halve_every(mut self, increments: u64) -> CountMinSketch {
+        self.halve_every = Some(increments.max(1));
+        self
+    }
+
+    /// Returns the number of counters per row.
+    #[inline]
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Returns the number of rows.
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    // Column of a key in a row, by double hashing
+    #[inline]
+    fn column(&self, h: u64, row: usize) -> usize {
+        let h2 = h.rotate_left(32) | 1;
+        (h.wrapping_add((row as u64).wrapping_mul(h2)) % self.width as u64) as usize
+    }
+
+    #[inline]
+    fn get(&self, row: usize, col: usize) -> u8 {
+        let i = row * self.width + col;
+        ((self.table[i / PER_WORD] >> (i % PER_WORD * 4)) & 0xF) as u8
+    }
+
+    #[inline]
+    fn add(&mut self, row: usize, col: usize) {
+        if self.get(row, col) < MAX {
+            let i = row * self.width + col;
+            self.table[i / PER_WORD] += 1 << (i % PER_WORD * 4);
+        }
+    }
+
+    /// Count an occurrence of a key.
+    pub fn increment<K: Hash + ?Sized>(&mut self, k: &K) {
+        let h = hash(self.seed, k);
+        let min = self.estimate_hash(h);
+        for row in 0..self.depth {
+            let col = self.column(h, row);
+            if self.get(row, col) == min {
+                self.add(row, col);
+            }
+        }
+        self.additions += 1;
+        if self.halve_every.is_some_and(|n| self.additions >= n) {
+            self.halve();
+        }
+    }
+
+    /// Returns the estimated frequency of a key, at most 15.
+    pub fn estimate<K: Hash + ?Sized>(&self, k: &K) -> u8 {
+        self.estimate_hash(hash(self.seed, k))
+    }
+
+    fn estimate_hash(&self, h: u64) -> u8 {
+        (0..self.depth)
+            .map(|row| self.get(row, self.column(h, row)))
+            .min()
+            .unwrap()
+    }
+
+    /// Halve all counters.
+    pub fn halve(&mut self) {
+        for word in self.table.iter_mut() {
+            *word = (*word >> 1) & 0x7777_7777_7777_7777;
+        }
+        self.additions /= 2;
+    }
+
+    /// Reset all counters.
+    pub fn clear(&mut self) {
+        self.table.iter_mut().for_each(|w| *w = 0);
+        self.additions = 0;
+    }
+}
+
+// Hash of a key with a seed
+#[inline]
+pub(crate) fn hash<K: Hash + ?Sized>(seed: u64, k: &K) -> u64 {
+    let mut h = DefaultHasher::new();
+    seed.hash(&mut h);
+    k.hash(&mut h);
+    h.finish()
+}
@@ -1,2 +1,5 @@
+/// A buffer manager of file pages, replaced with 2Q.
+pub mod buffer_pool;
 pub mod caching;
-mod common;
+/// Probabilistic data structures the policies are built on.
+pub mod common;
@@ -1,3 +1,73 @@
+// Helpers shared by the test modules
+mod helpers {
+    use papers_web_love::caching::Cache;
+    use std::fmt::Debug;
+    use std::fs::{self, File, OpenOptions};
+    use std::ops::Range;
+    use std::path::PathBuf;
+
+    // Key of the i-th access, a few hot keys among 41
+    pub fn key(i: u64) -> u64 {
+        if i.is_multiple_of(3) {
+            i % 5
+        } else {
+            i * 7 % 41
+        }
+    }
+
+    // Insert the keys of the accesses that miss
+    pub fn warm<V, C, F>(cache: &mut C, accesses: Range<u64>, value: F)
+    where
+        C: Cache<u64, V>,
+        F: Fn(u64) -> V,
+    {
+        for i in accesses {
+            let k = key(i);
+            if cache.get(&k).is_none() {
+                cache.insert(k, value(i));
+            }
+        }
+    }
+
+    // Drive both caches with the same accesses and compare what they do
+    pub fn assert_equivalent<V, C, D, F>(a: &mut C, b: &mut D, value: F)
+    where
+        V: Debug + PartialEq,
+        C: Cache<u64, V>,
+        D: Cache<u64, V>,
+        F: Fn(u64) -> V,
+    {
+        assert_eq!(a.len(), b.len());
+        for i in 0..2000u64 {
+            let k = (i * i + 3 * i) % 53;
+            assert_eq!(a.get(&k), b.get(&k));
+            if i % 3 == 0 {
+                assert_eq!(a.insert(k, value(i)), b.insert(k, value(i)));
+            }
+        }
+    }
+
+    // A path of the temp directory for this process, with nothing at it
+    pub fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    // An empty file at a temp path, open for reading and writing
+    pub fn temp_file(name: &str) -> (PathBuf, File) {
+        let path = temp_path(name);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        (path, file)
+    }
+}
 mod lfu {
     use papers_web_love::caching::lfu::LfuCache;
     #[test]
@@ -10,6 +80,9 @@ mod lfu {
         for i in 0..capacity * 100 {
             cache.insert(i % 3, i);
             cache.insert(i % 5, i);
+            if i % 7 == 0 {
+                cache.remove(&(i % 5));
+            }
             if i % 30 == 0 {
                 cache.clear();
             }
@@ -44,9 +117,60 @@ mod lfu {
         assert_eq!(cache.get(&5), Some(&5));
         assert_eq!(cache.get(&6), Some(&6));
     }
+
+    #[test]
+    fn remove() {
+        let mut cache = LfuCache::new(3);
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        cache.get(&0);
+        cache.get(&1);
+        cache.get(&1);
+        assert_eq!(cache.remove(&1), Some(1));
+        assert_eq!(cache.remove(&1), None);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.insert(3, 3), None);
+        assert_eq!(cache.insert(4, 4), Some((2, 2)));
+        assert_eq!(cache.insert(5, 5), Some((3, 3)));
+        assert_eq!(cache.get(&0), Some(&0));
+    }
+
+    #[test]
+    fn pinning() {
+        use papers_web_love::caching::{AllPinned, Cache, Insertion};
+        let mut cache = LfuCache::new(3);
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        assert!(cache.pin(&0) && cache.pin(&1) && cache.pin(&1));
+        assert!(!cache.pin(&7));
+        assert_eq!(cache.pinned_len(), 2);
+        // 0 is the least frequently used entry but is pinned
+        cache.get(&2);
+        assert_eq!(cache.insert(3, 3), Some((2, 2)));
+        assert!(cache.pin(&3));
+        assert!(matches!(cache.try_insert(4, 4), Err(AllPinned(4, 4))));
+        let dyn_cache: &mut dyn Cache<_, _> = &mut cache;
+        assert!(matches!(dyn_cache.try_insert(4, 4), Err(AllPinned(4, 4))));
+        assert_eq!(cache.insert(4, 4), None);
+        assert!(!cache.contains(&4));
+        // Updating a pinned entry needs no room
+        assert_eq!(cache.try_insert(0, 10).unwrap(), Insertion::Inserted);
+        assert_eq!(cache.get(&0), Some(&10));
+
+        // 0 was used twice while pinned, 1 never
+        assert!(cache.unpin(&0) && cache.unpin(&1));
+        assert!(!cache.unpin(&1) && !cache.is_pinned(&1));
+        assert_eq!(cache.insert(5, 5), Some((1, 1)));
+        assert_eq!(cache.insert(6, 6), Some((5, 5)));
+        assert_eq!(cache.remove(&3), Some(3));
+        assert_eq!(cache.pinned_len(), 0);
+        assert_eq!(cache.len(), 2);
+    }
 }
 mod lru_two_q {
-    use papers_web_love::caching::two_q_lru::SimplifiedTwoQ;
+    use papers_web_love::caching::two_q_lru::{Adaptive, SimplifiedTwoQ};
     #[test]
     fn basic() {
         let mut cache = SimplifiedTwoQ::with_threshold(10, 5);
@@ -96,6 +220,49 @@ mod lru_two_q {
         assert!(cache.get(&5).is_none());
     }
 
+    fn hit_ratio(cache: &mut SimplifiedTwoQ<u64, ()>, trace: &[u64]) -> f64 {
+        let mut hits = 0;
+        for k in trace {
+            if cache.get(k).is_some() {
+                hits += 1;
+            } else {
+                cache.insert(*k, ());
+            }
+        }
+        hits as f64 / trace.len() as f64
+    }
+
+    #[test]
+    fn adaptive() {
+        let adaptive = Adaptive {
+            sample_rate: 0.2,
+            interval: 200,
+            ..Adaptive::default()
+        };
+        // A warm hot set of 700 keys mixed with one-hit wonders needs a small A1
+        let mixed = (0..200_000u64).map(|i| {
+            if i % 2 == 0 {
+                i / 2 % 700
+            } else {
+                1_000_000 + i
+            }
+        });
+        let scans: Vec<u64> = (0..1400u64).map(|i| i % 700).chain(mixed).collect();
+        let mut cache = SimplifiedTwoQ::with_adaptive(1000, adaptive.clone());
+        let adaptive_ratio = hit_ratio(&mut cache, &scans);
+        assert!(cache.threshold() < 1000 / 3);
+        let static_ratio = hit_ratio(&mut SimplifiedTwoQ::with_capacity(1000), &scans);
+        assert!(adaptive_ratio > static_ratio);
+
+        // Keys accessed twice, 500 accesses apart, need a large A1
+        let pairs: Vec<u64> = (0..200_000u64).map(|i| i % 500 + i / 1000 * 500).collect();
+        let mut cache = SimplifiedTwoQ::with_adaptive(1000, adaptive);
+        let adaptive_ratio = hit_ratio(&mut cache, &pairs);
+        assert!(cache.threshold() > 1000 / 3);
+        let static_ratio = hit_ratio(&mut SimplifiedTwoQ::with_capacity(1000), &pairs);
+        assert!(adaptive_ratio > static_ratio);
+    }
+
     #[test]
     fn robust() {
         let mut cache = SimplifiedTwoQ::with_threshold(10, 5);
@@ -104,9 +271,2048 @@ mod lru_two_q {
             if i % 3 == 0 {
                 cache.get(&i);
             }
+            if i % 7 == 0 {
+                cache.remove(&(i - 5));
+            }
             if i % 100 == 0 {
                 cache.clear();
             }
         }
     }
+
+    #[test]
+    fn pinning() {
+        use papers_web_love::caching::AllPinned;
+        let mut cache = SimplifiedTwoQ::with_threshold(3, 1);
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        // A1 [2,1,0]
+        assert!(cache.pin(&0) && cache.pin(&1));
+        assert!(!cache.pin(&7));
+        assert_eq!(cache.insert(3, 3), Some((2, 2)));
+        assert!(cache.pin(&3));
+        assert_eq!(cache.pinned_len(), 3);
+        assert!(matches!(cache.try_insert(4, 4), Err(AllPinned(4, 4))));
+        assert_eq!(cache.insert(4, 4), None);
+        assert_eq!(cache.len(), 3);
+
+        // A hit on a pinned entry of A1 moves it to Am once unpinned
+        cache.get(&1);
+        assert!(cache.unpin(&0) && cache.unpin(&1));
+        assert!(!cache.unpin(&0));
+        // A1 [0], Am [1]
+        assert_eq!(cache.insert(5, 5), Some((0, 0)));
+        assert_eq!(cache.insert(6, 6), Some((5, 5)));
+        assert!(cache.is_pinned(&3));
+        assert_eq!(cache.remove(&3), Some(3));
+        assert_eq!(cache.pinned_len(), 0);
+        cache.pin(&1);
+        cache.clear();
+        assert_eq!(cache.pinned_len(), 0);
+    }
+}
+mod mq {
+    use papers_web_love::caching::mq::MultiQueue;
+    use std::hash::{Hash, Hasher};
+
+    #[test]
+    fn basic() {
+        let mut cache = MultiQueue::with_config(4, 4, 8, 100);
+        for i in 0..4 {
+            cache.insert(i, i);
+        }
+        // 0 and 1 are accessed often and climb to Q2
+        for _ in 0..4 {
+            assert_eq!(cache.get(&0), Some(&0));
+            assert_eq!(cache.get(&1), Some(&1));
+        }
+        // 2 and 3 stay in Q0 and are evicted first
+        cache.insert(4, 4);
+        cache.insert(5, 5);
+        assert!(cache.get(&2).is_none());
+        assert!(cache.get(&3).is_none());
+        assert_eq!(cache.get(&0), Some(&0));
+        assert_eq!(cache.get(&1), Some(&1));
+        assert_eq!(cache.len(), 4);
+    }
+
+    #[test]
+    fn history_and_expiration() {
+        let mut cache = MultiQueue::with_config(2, 4, 4, 4);
+        cache.insert(0, 0);
+        for _ in 0..3 {
+            cache.get(&0);
+        }
+        // 0 is idle long enough to be demoted back to Q0, then evicted
+        for i in 1..12 {
+            cache.insert(i, i);
+        }
+        assert!(cache.get(&0).is_none());
+        // Qout remembers the frequency of 0, so it is admitted at Q2
+        cache.insert(0, 0);
+        cache.insert(20, 20);
+        cache.insert(21, 21);
+        assert_eq!(cache.get(&0), Some(&0));
+    }
+
+    // Keys with the same first field share a fingerprint
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Colliding(u64, u64);
+
+    impl Hash for Colliding {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.0.hash(state);
+        }
+    }
+
+    #[test]
+    fn fingerprint_collision() {
+        let mut cache = MultiQueue::with_config(2, 4, 2, 100);
+        for &k in [
+            Colliding(1, 1),
+            Colliding(2, 0),
+            Colliding(2, 1),
+            Colliding(0, 0),
+        ]
+        .iter()
+        {
+            cache.insert(k, ());
+        }
+        // Qout holds the ghosts of 1 and 2, 0 climbs to Q1
+        cache.get(&Colliding(0, 0));
+        // The ghost of the evicted Colliding(2, 1) replaces the one of its
+        // fingerprint, the ghost of 1 stays and 1 comes back at Q1
+        cache.insert(Colliding(1, 1), ());
+        // 0 is older in Q1 and evicted
+        cache.insert(Colliding(0, 1), ());
+        assert!(cache.contains(&Colliding(1, 1)) && !cache.contains(&Colliding(0, 0)));
+    }
+
+    #[test]
+    fn robust() {
+        let mut cache = MultiQueue::with_capacity(10);
+        for i in 0..10000 {
+            cache.insert(i % 37, i);
+            if i % 3 == 0 {
+                cache.get(&(i % 7));
+            }
+            if i % 1000 == 0 {
+                cache.clear();
+            }
+            assert!(cache.len() <= 10);
+        }
+    }
+}
+mod gdsf {
+    use papers_web_love::caching::gdsf::GdsfCache;
+    #[test]
+    fn basic() {
+        let mut cache = GdsfCache::new(100);
+        // A cheap huge object and a small expensive one
+        cache.insert("huge", 0, 80, 1.0);
+        cache.insert("small", 1, 10, 10.0);
+        assert_eq!(cache.used(), 90);
+        // No room for another one, the cheap huge object goes first
+        assert_eq!(cache.insert("other", 2, 20, 1.0), vec![("huge", 0)]);
+        assert!(cache.get(&"huge").is_none());
+        assert_eq!(cache.get(&"small"), Some(&1));
+        assert_eq!(cache.get(&"other"), Some(&2));
+        assert_eq!(cache.used(), 30);
+        // L advanced to the priority of the evicted object
+        assert!((cache.inflation() - 1.0 / 80.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn frequency() {
+        let mut cache = GdsfCache::new(3);
+        cache.insert(1, 1, 1, 1.0);
+        cache.insert(2, 2, 1, 1.0);
+        cache.insert(3, 3, 1, 1.0);
+        cache.get(&1);
+        cache.get(&3);
+        cache.insert(4, 4, 1, 1.0);
+        assert!(cache.get(&2).is_none());
+        // Too large to be cached at all
+        cache.insert(5, 5, 4, 100.0);
+        assert!(cache.get(&5).is_none());
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn robust() {
+        let mut cache = GdsfCache::new(1000);
+        for i in 0..10000u64 {
+            cache.insert(i % 97, i, i % 50 + 1, (i % 7) as f64 + 0.5);
+            if i % 3 == 0 {
+                cache.get(&(i % 11));
+            }
+            if i % 1000 == 0 {
+                cache.clear();
+            }
+            assert!(cache.used() <= cache.capacity());
+        }
+    }
+}
+mod opt {
+    use papers_web_love::caching::lfu::LfuCache;
+    use papers_web_love::caching::opt::{hit_ratio, Belady};
+    #[test]
+    fn basic() {
+        // The classic example from the textbooks: 9 faults with 3 frames
+        let trace = [7, 0, 1, 2, 0, 3, 0, 4, 2, 3, 0, 3, 2, 1, 2, 0, 1, 7, 0, 1];
+        let stats = Belady::new(&trace).simulate(3);
+        assert_eq!(stats.requests, 20);
+        assert_eq!(stats.requests - stats.hits, 9);
+        assert_eq!(hit_ratio(&trace, 0), 0.0);
+        assert_eq!(hit_ratio(&trace, 8), 14.0 / 20.0);
+        assert_eq!(hit_ratio::<u32>(&[], 8), 0.0);
+    }
+
+    #[test]
+    fn upper_bound() {
+        let trace: Vec<u64> = (0..5000u64).map(|i| (i * i + 7 * i) % 113).collect();
+        let belady = Belady::new(&trace);
+        for capacity in (1..120).step_by(17) {
+            let mut cache = LfuCache::new(capacity);
+            let mut hits = 0;
+            for k in trace.iter() {
+                if cache.get(k).is_some() {
+                    hits += 1;
+                } else {
+                    cache.insert(*k, ());
+                }
+            }
+            assert!(belady.simulate(capacity).hits >= hits);
+        }
+    }
+}
+mod sim {
+    use papers_web_love::caching::admission::SeenTwice;
+    use papers_web_love::caching::lfu::LfuCache;
+    use papers_web_love::caching::sim::{builtin, replay, Access, Simulator, BUILTIN};
+    use papers_web_love::caching::two_q_lru::SimplifiedTwoQ;
+    #[test]
+    fn replay_stats() {
+        let trace: Vec<_> = [1, 2, 1, 3, 1, 2]
+            .iter()
+            .map(|&k| Access::with_size(k, k as u64 * 10))
+            .collect();
+        let mut cache = LfuCache::new(2);
+        let stats = replay(&mut cache, &trace);
+        // 1 hits twice, 3 evicts 2, then 2 evicts 3
+        assert_eq!(stats.requests, 6);
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.evictions, 2);
+        assert_eq!(stats.bytes, 100);
+        assert_eq!(stats.hit_bytes, 20);
+        assert!((stats.hit_ratio() - 2.0 / 6.0).abs() < 1e-9);
+        assert!((stats.byte_hit_ratio() - 0.2).abs() < 1e-9);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.insert(4, ()), Some((2, ())));
+
+        // The first miss of 3 is rejected, the second one evicts 2
+        let trace: Vec<_> = [1, 2, 3, 3].iter().map(|&k| Access::new(k)).collect();
+        let mut cache = LfuCache::new(2).with_admission(SeenTwice::new(10));
+        let stats = replay(&mut cache, &trace);
+        assert_eq!((stats.evictions, stats.rejections), (1, 1));
+    }
+
+    #[test]
+    fn simulator() {
+        let trace: Vec<_> = (0..3000u64).map(|i| Access::new(i * 7 % 41)).collect();
+        let capacities = [5, 20, 50];
+        let reports = Simulator::with_builtin()
+            .policy("2q-half", |cap| {
+                Box::new(SimplifiedTwoQ::with_threshold(cap, cap / 2))
+            })
+            .run(&trace, &capacities);
+        assert_eq!(reports.len(), (BUILTIN.len() + 1) * capacities.len());
+        for r in reports.iter() {
+            assert_eq!(r.stats.requests, 3000);
+            if r.capacity == 50 {
+                // Everything fits, only the cold misses remain
+                assert_eq!(r.stats.hits, 3000 - 41, "{}", r.policy);
+                assert_eq!(r.stats.evictions, 0, "{}", r.policy);
+            }
+        }
+        assert_eq!(reports.last().unwrap().policy, "2q-half");
+    }
+
+    #[test]
+    fn sized() {
+        // 100 small keys hot for a while, then 20 keys of size 4
+        let mut trace = vec![];
+        for i in 0..2000u64 {
+            trace.push(Access::with_size(i % 100, 1));
+        }
+        for i in 0..4000u64 {
+            trace.push(Access::with_size(1000 + i % 20, 4));
+        }
+        let reports = Simulator::with_builtin().run(&trace, &[100]);
+        let stats = |name: &str| reports.iter().find(|r| r.policy == name).unwrap().stats;
+        let (lfu, gdsf) = (stats("lfu"), stats("gdsf"));
+        assert!(gdsf.byte_hit_ratio() > lfu.byte_hit_ratio() + 0.5);
+
+        // Entries larger than the cache are rejected, and a large
+        // entry evicts as many small ones as it needs
+        let trace = [(1, 1), (2, 1), (3, 1), (4, 5), (5, 3)];
+        let trace: Vec<_> = trace
+            .iter()
+            .map(|&(k, size)| Access::with_size(k, size))
+            .collect();
+        let stats = replay(builtin::<u64>("gdsf").unwrap()(3).as_mut(), &trace);
+        assert_eq!((stats.evictions, stats.rejections), (3, 1));
+    }
+}
+mod trace {
+    use papers_web_love::caching::lfu::LfuCache;
+    use papers_web_love::caching::sim::{replay, Access};
+    use papers_web_love::caching::trace::{Format, TraceReader};
+    use std::io::Cursor;
+
+    fn read(format: Format, text: &str) -> Vec<Access<u64>> {
+        TraceReader::new(Cursor::new(text), format)
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn formats() {
+        let arc = read(Format::Arc, "10 3 0 1\n\n# comment\n7\n");
+        let keys: Vec<_> = arc.iter().map(|a| a.key).collect();
+        assert_eq!(keys, vec![10, 11, 12, 7]);
+        assert!(arc.iter().all(|a| a.size == 512));
+
+        let spc = read(Format::Spc, "0,20941264,8192,W,0.551706\n1,3,512,r,0.6\n");
+        assert_eq!(spc[0], Access::with_size(20941264, 8192));
+        assert_eq!(spc[1], Access::with_size((1 << 48) | 3, 512));
+
+        let msr = read(
+            Format::Msr,
+            "128166372003061629,hm,1,Read,383496192,32768,131\n",
+        );
+        assert_eq!(msr[0], Access::with_size((1 << 48) | 749016, 32768));
+
+        let simple = read(Format::Simple, "42\nfoo,100\nfoo\n");
+        assert_eq!(simple[0], Access::new(42));
+        assert_eq!(simple[1].size, 100);
+        assert_eq!(simple[1].key, simple[2].key);
+        assert_eq!("lirs".parse(), Ok(Format::Arc));
+        assert!("nope".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        let mut reader = TraceReader::new(Cursor::new("1\nx 2\n"), Format::Arc);
+        assert!(reader.next().unwrap().is_ok());
+        let err = reader.next().unwrap().unwrap_err();
+        assert!(err.to_string().starts_with("line 2:"));
+        let mut reader = TraceReader::new(Cursor::new("0,1\n"), Format::Spc);
+        assert!(reader.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn streaming_replay() {
+        let text: String = (0..1000).map(|i| format!("{} 2\n", i % 50 * 2)).collect();
+        let reader = TraceReader::new(Cursor::new(text), Format::Arc);
+        let mut cache = LfuCache::new(100);
+        let stats = replay(&mut cache, reader.map(Result::unwrap));
+        assert_eq!(stats.requests, 2000);
+        assert_eq!(stats.hits, 2000 - 100);
+    }
+}
+mod cachesim {
+    use crate::helpers::temp_path;
+    use std::fs;
+    use std::process::Command;
+
+    #[test]
+    fn csv() {
+        let path = temp_path("cachesim.trace");
+        let text: String = (0..1000).map(|i| format!("{}\n", i % 20)).collect();
+        fs::write(&path, text).unwrap();
+        let out = Command::new(env!("CARGO_BIN_EXE_cachesim"))
+            .args(["-p", "lfu,2q-simple,opt", "-c", "10:30:7", "--csv"])
+            .arg(&path)
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        let lines: Vec<_> = stdout.lines().collect();
+        assert_eq!(lines.len(), 1 + 3 * 3);
+        assert!(lines[0].starts_with("policy,capacity"));
+        assert!(lines.contains(&"lfu,24,1000,0.9800,0.9800,0,0"));
+        assert!(lines.contains(&"opt,17,1000,0.8270,-,-,-"));
+
+        // The step defaults to the start, opt is not run by default
+        let out = Command::new(env!("CARGO_BIN_EXE_cachesim"))
+            .args(["-c", "10:30", "--csv"])
+            .arg(&path)
+            .output()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        let lines: Vec<_> = stdout.lines().collect();
+        assert_eq!(lines.len(), 1 + 6 * 3);
+        assert!(lines.iter().any(|l| l.starts_with("gdsf,20,")));
+        assert!(!lines.iter().any(|l| l.starts_with("opt,")));
+    }
+
+    #[test]
+    fn bad_args() {
+        let out = Command::new(env!("CARGO_BIN_EXE_cachesim"))
+            .args(["-p", "nope", "missing.trace"])
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(1));
+        let out = Command::new(env!("CARGO_BIN_EXE_cachesim"))
+            .args(["-c", "10:5"])
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(2));
+    }
+}
+mod mrc {
+    use papers_web_love::caching::mrc::{MissRatioCurve, StackDistance};
+    use std::collections::VecDeque;
+
+    // Misses of a naive LRU
+    fn lru_misses(trace: &[u64], size: usize) -> usize {
+        let mut lru = VecDeque::new();
+        let mut misses = 0;
+        for k in trace {
+            match lru.iter().position(|x| x == k) {
+                Some(i) => {
+                    lru.remove(i);
+                }
+                None => {
+                    misses += 1;
+                    if lru.len() == size {
+                        lru.pop_back();
+                    }
+                }
+            }
+            if size > 0 {
+                lru.push_front(*k);
+            }
+        }
+        misses
+    }
+
+    #[test]
+    fn stack_distance() {
+        let mut stack = StackDistance::new();
+        assert_eq!(stack.access('a'), None);
+        assert_eq!(stack.access('b'), None);
+        assert_eq!(stack.access('c'), None);
+        assert_eq!(stack.access('a'), Some(2));
+        assert_eq!(stack.access('a'), Some(0));
+        assert_eq!(stack.access('b'), Some(2));
+        stack.forget(&'c');
+        assert_eq!(stack.access('a'), Some(1));
+        assert_eq!(stack.len(), 2);
+    }
+
+    #[test]
+    fn exact_curve() {
+        let trace: Vec<u64> = (0..5000u64).map(|i| (i * i + i / 3) % 97).collect();
+        let mrc = MissRatioCurve::from_trace(trace.iter().cloned());
+        assert_eq!(mrc.requests(), 5000.0);
+        let curve = mrc.curve();
+        assert_eq!(curve[0], 1.0);
+        for size in [0, 1, 5, 17, 40, 96, 97, 200].iter().cloned() {
+            let expected = lru_misses(&trace, size) as f64 / 5000.0;
+            assert!((mrc.miss_ratio(size) - expected).abs() < 1e-9, "{}", size);
+            if size < curve.len() {
+                assert!((curve[size] - expected).abs() < 1e-9);
+            }
+        }
+        assert_eq!(
+            MissRatioCurve::from_trace(Vec::<u64>::new()).miss_ratio(3),
+            0.0
+        );
+    }
+}
+mod shards {
+    use papers_web_love::caching::mrc::MissRatioCurve;
+    use papers_web_love::caching::shards::{Shards, SpatialSampler};
+
+    // Skewed trace over 20000 keys
+    fn trace() -> Vec<u64> {
+        let mut x = 42u64;
+        (0..200_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                let r = (x % 1_000_000) as f64 / 1_000_000.0;
+                (r * r * r * 20_000.0) as u64
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sampler() {
+        let sampler = SpatialSampler::new(0.1);
+        assert!((sampler.rate() - 0.1).abs() < 1e-6);
+        let sampled = (0..100_000u64).filter(|k| sampler.sample(k)).count();
+        assert!(sampled > 9_000 && sampled < 11_000);
+        assert!((0..100u64).all(|k| SpatialSampler::new(1.0).sample(&k)));
+    }
+
+    #[test]
+    fn approximate_curve() {
+        let trace = trace();
+        let exact = MissRatioCurve::from_trace(trace.iter().cloned());
+        let mut fixed_rate = Shards::fixed_rate(0.1);
+        let mut fixed_size = Shards::fixed_size(4000);
+        for k in trace.iter() {
+            fixed_rate.access(k);
+            fixed_size.access(k);
+        }
+        assert!(fixed_size.tracked() <= 4000);
+        assert!(fixed_size.rate() < 0.5);
+        let (fixed_rate, fixed_size) = (fixed_rate.mrc(), fixed_size.mrc());
+        for &size in [500, 1000, 2000, 5000, 10000].iter() {
+            let expected = exact.miss_ratio(size);
+            assert!((fixed_rate.miss_ratio(size) - expected).abs() < 0.03);
+            assert!((fixed_size.miss_ratio(size) - expected).abs() < 0.03);
+        }
+    }
+}
+#[cfg(feature = "serde")]
+mod serde {
+    use crate::helpers::{assert_equivalent, warm};
+    use papers_web_love::caching::lfu::LfuCache;
+    use papers_web_love::caching::two_q_lru::SimplifiedTwoQ;
+
+    #[test]
+    fn lfu() {
+        let mut cache = LfuCache::new(20);
+        warm(&mut cache, 0..500, |i| i);
+        let json = serde_json::to_string(&cache).unwrap();
+        let mut restored: LfuCache<u64, u64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.capacity(), 20);
+        assert_equivalent(&mut cache, &mut restored, |i| i);
+    }
+
+    #[test]
+    fn two_q() {
+        let mut cache = SimplifiedTwoQ::with_threshold(20, 7);
+        warm(&mut cache, 0..500, |i| i);
+        let json = serde_json::to_string(&cache).unwrap();
+        let mut restored: SimplifiedTwoQ<u64, u64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.threshold(), 7);
+        assert_equivalent(&mut cache, &mut restored, |i| i);
+        assert!(serde_json::from_str::<SimplifiedTwoQ<u64, u64>>("{}").is_err());
+    }
+}
+mod snapshot {
+    use crate::helpers::{assert_equivalent, temp_path, warm};
+    use papers_web_love::caching::lfu::LfuCache;
+    use papers_web_love::caching::snapshot::{self, Snapshot};
+    use papers_web_love::caching::two_q_lru::SimplifiedTwoQ;
+
+    fn value(i: u64) -> String {
+        format!("value {}", i)
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut lfu = LfuCache::new(20);
+        warm(&mut lfu, 0..500, value);
+        let mut buf = vec![];
+        lfu.write_snapshot(&mut buf).unwrap();
+        let mut restored = LfuCache::read_snapshot(&buf[..]).unwrap();
+        assert_equivalent(&mut lfu, &mut restored, value);
+
+        let mut two_q = SimplifiedTwoQ::with_threshold(20, 7);
+        warm(&mut two_q, 0..500, value);
+        let path = temp_path("snapshot.bin");
+        snapshot::save(&two_q, &path).unwrap();
+        let mut restored: SimplifiedTwoQ<u64, String> = snapshot::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored.threshold(), 7);
+        assert_equivalent(&mut two_q, &mut restored, value);
+
+        // Pinned entries are restored unpinned
+        let mut lfu = LfuCache::new(20);
+        warm(&mut lfu, 0..500, value);
+        lfu.pin(&0);
+        lfu.pin(&1);
+        let mut buf = vec![];
+        lfu.write_snapshot(&mut buf).unwrap();
+        let restored = LfuCache::<u64, String>::read_snapshot(&buf[..]).unwrap();
+        assert_eq!(restored.len(), lfu.len());
+        assert_eq!(restored.peek(&1), lfu.peek(&1));
+        assert_eq!(restored.pinned_len(), 0);
+
+        // Large caches span many blocks
+        let mut big = LfuCache::new(100_000);
+        for i in 0..100_000u64 {
+            big.insert(i, i.to_string());
+        }
+        let mut buf = vec![];
+        big.write_snapshot(&mut buf).unwrap();
+        assert_eq!(
+            LfuCache::<u64, String>::read_snapshot(&buf[..])
+                .unwrap()
+                .len(),
+            100_000
+        );
+    }
+
+    #[test]
+    fn reject_damaged() {
+        let mut cache = SimplifiedTwoQ::with_threshold(20, 7);
+        warm(&mut cache, 0..100, value);
+        let mut buf = vec![];
+        cache.write_snapshot(&mut buf).unwrap();
+        for len in 0..buf.len() {
+            assert!(SimplifiedTwoQ::<u64, String>::read_snapshot(&buf[..len]).is_err());
+        }
+        for i in 0..buf.len() {
+            let mut damaged = buf.clone();
+            damaged[i] ^= 0x40;
+            assert!(SimplifiedTwoQ::<u64, String>::read_snapshot(&damaged[..]).is_err());
+        }
+        // Snapshot of another policy
+        assert!(LfuCache::<u64, String>::read_snapshot(&buf[..]).is_err());
+    }
+}
+mod wal {
+    use crate::helpers::{assert_equivalent, key, temp_path};
+    use papers_web_love::caching::admission::TinyLfu;
+    use papers_web_love::caching::lfu::LfuCache;
+    use papers_web_love::caching::snapshot::Snapshot;
+    use papers_web_love::caching::two_q_lru::{Adaptive, SimplifiedTwoQ};
+    use papers_web_love::caching::wal::{self, Journaled, LogWriter};
+    use papers_web_love::caching::Cache;
+    use std::fs;
+    use std::io;
+
+    fn value(i: u64) -> String {
+        format!("value {}", i)
+    }
+
+    // Mix of accesses, updates and removals with a skewed key popularity
+    fn drive<C: Cache<u64, String>>(cache: &mut C, from: u64, to: u64) {
+        for i in from..to {
+            let k = key(i);
+            if i % 17 == 0 {
+                cache.remove(&k);
+            } else if cache.get(&k).is_none() || i % 11 == 0 {
+                cache.insert(k, value(i));
+            }
+        }
+    }
+
+    #[test]
+    fn replay() {
+        let mut cache = LfuCache::new(20);
+        drive(&mut cache, 0, 300);
+        let mut snapshot = vec![];
+        cache.write_snapshot(&mut snapshot).unwrap();
+
+        // Log what happens after the snapshot
+        let mut log = LogWriter::new(vec![]).unwrap();
+        let mut records = 0;
+        for i in 300..600u64 {
+            let k = i * 7 % 41;
+            if i % 13 == 0 {
+                if cache.remove(&k).is_some() {
+                    log.remove(&k).unwrap();
+                    records += 1;
+                }
+            } else if cache.contains(&k) {
+                log.access(&k).unwrap();
+                cache.get(&k);
+                records += 1;
+            } else {
+                log.insert(&k, &i.to_string()).unwrap();
+                cache.insert(k, i.to_string());
+                records += 1;
+            }
+        }
+        let log = log.get_ref();
+
+        let mut restored = LfuCache::read_snapshot(&snapshot[..]).unwrap();
+        assert_eq!(wal::replay(&mut restored, &log[..]).unwrap(), records);
+        assert_equivalent(&mut cache, &mut restored, value);
+
+        // A torn record is ignored
+        let mut restored = LfuCache::<u64, String>::read_snapshot(&snapshot[..]).unwrap();
+        let torn = &log[..log.len() - 3];
+        assert_eq!(wal::replay(&mut restored, torn).unwrap(), records - 1);
+        let mut damaged = log.clone();
+        damaged[10] ^= 1;
+        assert_eq!(wal::replay(&mut restored, &damaged[..]).unwrap(), 0);
+        assert!(wal::replay(&mut restored, &b"not a log"[..]).is_err());
+    }
+
+    #[test]
+    fn restart() {
+        let dir = temp_path("wal-restart");
+        let mut expected = SimplifiedTwoQ::with_threshold(20, 5);
+        let mut journal = Journaled::open(&dir, SimplifiedTwoQ::with_threshold(20, 5)).unwrap();
+        drive(&mut expected, 0, 500);
+        drive(&mut journal, 0, 500);
+        journal.checkpoint().unwrap();
+        drive(&mut expected, 500, 800);
+        drive(&mut journal, 500, 800);
+        journal.sync().unwrap();
+        assert!(journal.log_len() > 0);
+        // Killed without a checkpoint
+        drop(journal);
+
+        let mut journal = Journaled::open(&dir, SimplifiedTwoQ::with_capacity(1)).unwrap();
+        assert_eq!(journal.cache().threshold(), 5);
+        assert_equivalent(&mut expected, &mut journal, value);
+        journal.sync().unwrap();
+        drop(journal);
+
+        // Torn tail after a crash in the middle of an append
+        let log = dir.join("log.1");
+        let mut bytes = fs::read(&log).unwrap();
+        let len = bytes.len();
+        bytes.extend_from_slice(&[9, 0, 0, 0, 3, 1]);
+        fs::write(&log, &bytes).unwrap();
+        let mut journal: Journaled<u64, String, SimplifiedTwoQ<u64, String>> =
+            Journaled::open(&dir, SimplifiedTwoQ::with_capacity(1)).unwrap();
+        assert_eq!(fs::metadata(&log).unwrap().len(), len as u64);
+        // New records follow the intact ones
+        drive(&mut expected, 800, 900);
+        drive(&mut journal, 800, 900);
+        journal.sync().unwrap();
+        drop(journal);
+        let mut journal = Journaled::open(&dir, SimplifiedTwoQ::with_capacity(1)).unwrap();
+        assert_equivalent(&mut expected, &mut journal, value);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn incomplete_snapshot() {
+        let dir = temp_path("wal-incomplete");
+        let lfu = LfuCache::<u64, String>::new(20).with_admission(TinyLfu::new(20));
+        let err = Journaled::open(&dir, lfu).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let q = SimplifiedTwoQ::<u64, String>::with_adaptive(20, Adaptive::default());
+        let err = Journaled::open(&dir, q).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn compaction() {
+        let dir = temp_path("wal-compaction");
+        let mut expected = LfuCache::new(20);
+        let mut journal = Journaled::open(&dir, LfuCache::new(20))
+            .unwrap()
+            .compact_every(100);
+        drive(&mut expected, 0, 1000);
+        drive(&mut journal, 0, 1000);
+        assert!(journal.log_len() <= 100);
+        journal.sync().unwrap();
+        drop(journal);
+
+        // Only the last generation is kept
+        let mut names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 2);
+        assert!(names[0].starts_with("log.") && names[1].starts_with("snapshot."));
+        assert_eq!(names[0][4..], names[1][9..]);
+
+        let mut journal = Journaled::open(&dir, LfuCache::new(20)).unwrap();
+        assert_equivalent(&mut expected, &mut journal, value);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+mod loading {
+    use papers_web_love::caching::lfu::LfuCache;
+    use papers_web_love::caching::loading::{CacheLoader, LoadingCache};
+    use papers_web_love::caching::two_q_lru::SimplifiedTwoQ;
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    // Loads k * 10, fails for odd keys
+    struct Loader {
+        calls: Cell<u32>,
+    }
+
+    impl CacheLoader<u64, u64> for Loader {
+        type Error = String;
+
+        fn load(&self, k: &u64) -> Result<u64, String> {
+            self.calls.set(self.calls.get() + 1);
+            if k % 2 == 1 {
+                Err(format!("no value for {}", k))
+            } else {
+                Ok(k * 10)
+            }
+        }
+    }
+
+    fn loader() -> Loader {
+        Loader {
+            calls: Cell::new(0),
+        }
+    }
+
+    #[test]
+    fn read_through() {
+        let mut cache = LoadingCache::new(LfuCache::new(2), loader());
+        assert_eq!(cache.get(&2).as_deref(), Ok(&20));
+        assert_eq!(cache.get(&2).as_deref(), Ok(&20));
+        assert_eq!(cache.loader().calls.get(), 1);
+        assert_eq!(cache.get_if_present(&4), None);
+        assert_eq!(cache.get(&4).as_deref(), Ok(&40));
+        assert_eq!(cache.get(&6).as_deref(), Ok(&60));
+        // 4 was loaded once, so it was evicted before 2
+        assert_eq!(cache.get_if_present(&4), None);
+        assert_eq!(cache.get(&2).as_deref(), Ok(&20));
+        assert_eq!(cache.loader().calls.get(), 3);
+
+        // Errors are not cached by default
+        assert_eq!(cache.get(&3), Err("no value for 3".to_string()));
+        assert_eq!(cache.get(&3), Err("no value for 3".to_string()));
+        assert_eq!(cache.loader().calls.get(), 5);
+        assert_eq!(cache.cache().len(), 2);
+
+        cache.insert(3, 7);
+        assert_eq!(cache.get(&3).as_deref(), Ok(&7));
+        assert_eq!(cache.invalidate(&3), Some(7));
+        assert!(cache.get(&3).is_err());
+    }
+
+    #[test]
+    fn closure() {
+        let calls = Cell::new(0);
+        let load = |k: &u64| {
+            calls.set(calls.get() + 1);
+            k.checked_sub(1).ok_or(())
+        };
+        let mut cache = LoadingCache::new(SimplifiedTwoQ::with_capacity(10), load);
+        assert_eq!(cache.get(&5).as_deref(), Ok(&4));
+        assert_eq!(cache.get(&5).as_deref(), Ok(&4));
+        assert_eq!(cache.get(&0), Err(()));
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn negative() {
+        let ttl = Duration::from_millis(50);
+        let mut cache = LoadingCache::new(LfuCache::new(10), loader()).cache_errors(10, ttl);
+        assert!(cache.get(&1).is_err());
+        assert!(cache.get(&1).is_err());
+        assert_eq!(cache.loader().calls.get(), 1);
+        // Errors do not take room from values
+        assert_eq!(cache.cache().len(), 0);
+
+        std::thread::sleep(ttl * 2);
+        assert!(cache.get(&1).is_err());
+        assert_eq!(cache.loader().calls.get(), 2);
+
+        // Insert and invalidate drop the error
+        cache.insert(1, 1);
+        assert_eq!(cache.get(&1).as_deref(), Ok(&1));
+        cache.invalidate(&1);
+        assert!(cache.get(&1).is_err());
+        assert_eq!(cache.loader().calls.get(), 3);
+        cache.invalidate(&1);
+        assert!(cache.get(&1).is_err());
+        assert_eq!(cache.loader().calls.get(), 4);
+    }
+
+    // Loads k * 100 + number of loads, can be made to fail, slow
+    // or to wait at a barrier
+    #[derive(Default)]
+    struct Versioned {
+        calls: AtomicU64,
+        fail: AtomicBool,
+        slow: AtomicBool,
+        barrier: Mutex<Option<Arc<Barrier>>>,
+    }
+
+    impl CacheLoader<u64, u64> for Versioned {
+        type Error = ();
+
+        fn load(&self, k: &u64) -> Result<u64, ()> {
+            let calls = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            let barrier = self.barrier.lock().unwrap().clone();
+            if let Some(barrier) = barrier {
+                barrier.wait();
+            }
+            if self.slow.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(100));
+            }
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(());
+            }
+            Ok(k * 100 + calls)
+        }
+    }
+
+    #[test]
+    fn expire() {
+        let ttl = Duration::from_millis(50);
+        let mut cache =
+            LoadingCache::new(LfuCache::new(10), Versioned::default()).expire_after_write(ttl);
+        assert_eq!(cache.get(&1).as_deref(), Ok(&101));
+        assert_eq!(cache.get(&1).as_deref(), Ok(&101));
+        thread::sleep(ttl + ttl / 2);
+        assert_eq!(cache.get_if_present(&1), None);
+        assert_eq!(cache.cache().len(), 0);
+        assert_eq!(cache.get(&1).as_deref(), Ok(&102));
+    }
+
+    #[test]
+    fn refresh() {
+        let interval = Duration::from_millis(50);
+        let mut cache = LoadingCache::new(LfuCache::new(10), Versioned::default())
+            .refresh_after_write(interval);
+        assert_eq!(cache.get(&1).as_deref(), Ok(&101));
+        thread::sleep(interval + interval / 2);
+        // The stale value is served while it is reloaded
+        assert_eq!(cache.get(&1).as_deref(), Ok(&101));
+        let mut tries = 0;
+        while cache.get(&1).as_deref() != Ok(&102) {
+            tries += 1;
+            assert!(tries < 200, "reload did not finish");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(cache.loader().calls.load(Ordering::SeqCst), 2);
+
+        // A failed reload keeps the value
+        cache.loader().fail.store(true, Ordering::SeqCst);
+        thread::sleep(interval + interval / 2);
+        assert_eq!(cache.get(&1).as_deref(), Ok(&102));
+        thread::sleep(interval);
+        assert_eq!(cache.get(&1).as_deref(), Ok(&102));
+        assert!(cache.loader().calls.load(Ordering::SeqCst) >= 3);
+
+        // A write during the reload wins
+        cache.loader().fail.store(false, Ordering::SeqCst);
+        cache.loader().slow.store(true, Ordering::SeqCst);
+        thread::sleep(interval * 2);
+        assert_eq!(cache.get(&1).as_deref(), Ok(&102));
+        cache.insert(1, 7);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(cache.get(&1).as_deref(), Ok(&7));
+    }
+
+    #[test]
+    fn reload_threads() {
+        let interval = Duration::from_millis(50);
+        let mut cache = LoadingCache::new(LfuCache::new(10), Versioned::default())
+            .refresh_after_write(interval)
+            .reload_threads(1);
+        for k in 0..3 {
+            cache.get(&k).unwrap();
+        }
+        let barrier = Arc::new(Barrier::new(2));
+        *cache.loader().barrier.lock().unwrap() = Some(barrier.clone());
+        thread::sleep(interval + interval / 2);
+        for k in 0..3 {
+            cache.get(&k).unwrap();
+        }
+        // One reload runs, the other stale entries wait for a later call
+        barrier.wait();
+        assert_eq!(cache.loader().calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn no_capacity() {
+        let mut cache = LoadingCache::new(LfuCache::new(0), Versioned::default());
+        // Every get loads, nothing is cached
+        assert_eq!(cache.get(&1).as_deref(), Ok(&101));
+        assert_eq!(cache.get(&1).as_deref(), Ok(&102));
+        assert_eq!(cache.cache().len(), 0);
+    }
+
+    #[test]
+    fn warm_cache() {
+        let ttl = Duration::from_millis(50);
+        let mut warm = LfuCache::new(10);
+        warm.insert(1, 5);
+        let mut cache = LoadingCache::new(warm, Versioned::default()).expire_after_write(ttl);
+        // Counts as written when first read
+        assert_eq!(cache.get(&1).as_deref(), Ok(&5));
+        thread::sleep(ttl + ttl / 2);
+        assert_eq!(cache.get(&1).as_deref(), Ok(&101));
+
+        let mut warm = LfuCache::new(10);
+        warm.insert(1, 5);
+        let mut cache = LoadingCache::new(warm, Versioned::default()).refresh_after_write(ttl);
+        assert_eq!(cache.get(&1).as_deref(), Ok(&5));
+        assert_eq!(cache.loader().calls.load(Ordering::SeqCst), 0);
+    }
+}
+mod sync {
+    use papers_web_love::caching::lfu::LfuCache;
+    use papers_web_love::caching::sync::{LoadError, SharedCache};
+    use papers_web_love::caching::two_q_lru::SimplifiedTwoQ;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::Duration;
+
+    const THREADS: usize = 8;
+
+    // Every thread misses the same key at once
+    fn stampede<F>(
+        cache: &Arc<SharedCache<u64, u64, LfuCache<u64, u64>>>,
+        load: F,
+    ) -> Vec<Result<u64, LoadError<String>>>
+    where
+        F: Fn(&u64) -> Result<u64, String> + Send + Sync + 'static,
+    {
+        let load = Arc::new(load);
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let (cache, barrier, load) = (cache.clone(), barrier.clone(), load.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    cache.get_or_load(&1, |k| load(k))
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    }
+
+    fn slow_load(calls: &Arc<AtomicUsize>) -> impl Fn(&u64) -> Result<u64, String> {
+        let calls = calls.clone();
+        move |k| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            Ok(k * 10)
+        }
+    }
+
+    #[test]
+    fn shared() {
+        let cache = Arc::new(SharedCache::new(SimplifiedTwoQ::with_capacity(100)));
+        let handles: Vec<_> = (0..THREADS as u64)
+            .map(|t| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        let k = t * 1000 + i % 50;
+                        if cache.get(&k).is_none() {
+                            cache.insert(k, k);
+                        }
+                    }
+                })
+            })
+            .collect();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        assert_eq!(cache.len(), 100);
+        assert_eq!(cache.with(|c| c.threshold()), 33);
+    }
+
+    #[test]
+    fn single_flight() {
+        let cache = Arc::new(SharedCache::new(LfuCache::new(10)).single_flight());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let results = stampede(&cache, slow_load(&calls));
+        assert!(results.iter().all(|r| r == &Ok(10)));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.get(&1), Some(10));
+
+        // Errors reach every waiter and are not cached
+        cache.clear();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let results = stampede(&cache, move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            Err("unavailable".to_string())
+        });
+        assert!(results
+            .iter()
+            .all(|r| r == &Err(LoadError::Failed("unavailable".to_string()))));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn panicking_loader() {
+        let cache = Arc::new(SharedCache::new(LfuCache::new(10)).single_flight());
+        let results = stampede(&cache, |_| {
+            thread::sleep(Duration::from_millis(100));
+            panic!("loader bug")
+        });
+        assert!(results
+            .iter()
+            .all(|r| r == &Err(LoadError::Panicked("loader bug".to_string()))));
+
+        // The cache is not poisoned
+        let calls = Arc::new(AtomicUsize::new(0));
+        let results = stampede(&cache, slow_load(&calls));
+        assert!(results.iter().all(|r| r == &Ok(10)));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            cache.get_or_load(&2, |_| -> Result<u64, ()> { panic!("{}", 2) }),
+            Err(LoadError::Panicked("2".to_string()))
+        );
+        assert_eq!(cache.len(), 1);
+    }
+}
+mod async_loading {
+    use papers_web_love::caching::async_loading::AsyncLoadingCache;
+    use papers_web_love::caching::lfu::LfuCache;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+    use std::time::Duration;
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    // Minimal executor: poll on the current thread, park while pending
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let mut fut = Box::pin(fut);
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+                return out;
+            }
+            thread::park();
+        }
+    }
+
+    #[derive(Default)]
+    struct Woken(AtomicUsize);
+
+    impl Wake for Woken {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // A future completed from the outside
+    #[derive(Default)]
+    struct GateState {
+        res: Option<Result<u64, String>>,
+        waker: Option<Waker>,
+    }
+
+    #[derive(Default)]
+    struct Gate {
+        state: Mutex<GateState>,
+    }
+
+    impl Gate {
+        fn open(&self, res: Result<u64, String>) {
+            let mut state = self.state.lock().unwrap();
+            state.res = Some(res);
+            if let Some(w) = state.waker.take() {
+                w.wake();
+            }
+        }
+    }
+
+    struct Wait(Arc<Gate>);
+
+    impl Future for Wait {
+        type Output = Result<u64, String>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let mut state = self.0.state.lock().unwrap();
+            match state.res.clone() {
+                Some(res) => Poll::Ready(res),
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+    }
+
+    fn poll<F: Future + Unpin>(fut: &mut F, woken: &Arc<Woken>) -> Poll<F::Output> {
+        let waker = Waker::from(woken.clone());
+        Pin::new(fut).poll(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn coalescing() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let cache = Arc::new(AsyncLoadingCache::new(LfuCache::new(10), move |k: &u64| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            // Completed by another thread, like a timer of a runtime
+            let gate = Arc::new(Gate::default());
+            let (opener, k) = (gate.clone(), *k);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                opener.open(Ok(k * 10));
+            });
+            Wait(gate)
+        }));
+        let barrier = Arc::new(Barrier::new(8));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let (cache, barrier) = (cache.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    block_on(cache.get(7))
+                })
+            })
+            .collect();
+        for h in handles {
+            assert_eq!(h.join().unwrap(), Ok(70));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.get_if_present(&7), Some(70));
+        assert_eq!(block_on(cache.get(7)), Ok(70));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.loading(), 0);
+    }
+
+    #[test]
+    fn no_capacity() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let cache = AsyncLoadingCache::new(LfuCache::new(0), move |k: &u64| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            std::future::ready(Ok::<u64, String>(k * 10))
+        });
+        assert_eq!(block_on(cache.get(7)), Ok(70));
+        assert_eq!(block_on(cache.get(7)), Ok(70));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn cancellation() {
+        let gates = Arc::new(Mutex::new(vec![]));
+        let created = gates.clone();
+        let cache = AsyncLoadingCache::new(LfuCache::new(10), move |_: &u64| {
+            let gate = Arc::new(Gate::default());
+            created.lock().unwrap().push(gate.clone());
+            Wait(gate)
+        });
+        let (woken_a, woken_b) = (Arc::new(Woken::default()), Arc::new(Woken::default()));
+        let mut a = cache.get(1);
+        let mut b = cache.get(1);
+        assert!(poll(&mut a, &woken_a).is_pending());
+        assert!(poll(&mut b, &woken_b).is_pending());
+        assert_eq!(gates.lock().unwrap().len(), 1);
+
+        // b keeps the load going after a, which polled it last, is dropped
+        drop(a);
+        gates.lock().unwrap()[0].open(Ok(5));
+        assert_eq!(woken_b.0.load(Ordering::SeqCst), 1);
+        assert_eq!(poll(&mut b, &woken_b), Poll::Ready(Ok(5)));
+        drop(b);
+        assert_eq!(cache.get_if_present(&1), Some(5));
+
+        // Dropping every waiter cancels the load
+        let mut c = cache.get(2);
+        assert!(poll(&mut c, &woken_a).is_pending());
+        assert_eq!(cache.loading(), 1);
+        drop(c);
+        assert_eq!(cache.loading(), 0);
+        assert_eq!(Arc::strong_count(&gates.lock().unwrap()[1]), 1);
+
+        // Errors reach every waiter and are not cached
+        let mut d = cache.get(2);
+        let mut e = cache.get(2);
+        assert!(poll(&mut d, &woken_a).is_pending());
+        assert!(poll(&mut e, &woken_b).is_pending());
+        gates.lock().unwrap()[2].open(Err("down".to_string()));
+        assert_eq!(poll(&mut e, &woken_b), Poll::Ready(Err("down".to_string())));
+        assert_eq!(poll(&mut d, &woken_a), Poll::Ready(Err("down".to_string())));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(gates.lock().unwrap().len(), 3);
+    }
+}
+mod writeback {
+    use papers_web_love::caching::lfu::LfuCache;
+    use papers_web_love::caching::two_q_lru::SimplifiedTwoQ;
+    use papers_web_love::caching::writeback::{BackingStore, WriteBack};
+    use papers_web_love::caching::Loaded;
+    use std::collections::HashMap;
+
+    // A store counting its writes, which can be made to fail
+    #[derive(Default)]
+    struct Store {
+        data: HashMap<u64, u64>,
+        writes: usize,
+        fail: bool,
+        // A key whose writes always fail
+        broken: Option<u64>,
+    }
+
+    impl BackingStore<u64, u64> for Store {
+        type Error = String;
+
+        fn read(&mut self, k: &u64) -> Result<Option<u64>, String> {
+            Ok(self.data.get(k).cloned())
+        }
+
+        fn write(&mut self, k: &u64, v: &u64) -> Result<(), String> {
+            if self.fail || self.broken == Some(*k) {
+                return Err("store unavailable".to_string());
+            }
+            self.writes += 1;
+            self.data.insert(*k, *v);
+            Ok(())
+        }
+
+        fn delete(&mut self, k: &u64) -> Result<(), String> {
+            self.data.remove(k);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_back() {
+        let mut store = Store::default();
+        store.data.insert(100, 1);
+        let mut cache = WriteBack::new(LfuCache::new(4), store);
+        assert_eq!(cache.get(&100), Ok(Some(Loaded::Cached(&1))));
+        assert_eq!(cache.get(&101), Ok(None));
+        assert!(!cache.is_dirty(&100));
+
+        for i in 0..3 {
+            cache.put(i, i).unwrap();
+            cache.put(i, i * 10).unwrap();
+        }
+        assert_eq!(cache.dirty_len(), 3);
+        assert_eq!(cache.store().writes, 0);
+        assert_eq!(cache.get(&1), Ok(Some(Loaded::Cached(&10))));
+
+        // Evicting a clean entry writes nothing, evicting a dirty one writes it
+        cache.put(3, 30).unwrap();
+        assert_eq!(cache.store().writes, 0);
+        assert!(!cache.cache().contains(&100));
+        cache.put(4, 40).unwrap();
+        assert_eq!(cache.store().writes, 1);
+        assert_eq!(cache.store().data.get(&3), Some(&30));
+        assert_eq!(cache.get(&3), Ok(Some(Loaded::Cached(&30))));
+
+        cache.flush_key(&1).unwrap();
+        assert!(!cache.is_dirty(&1));
+        assert_eq!(cache.store().data.get(&1), Some(&10));
+        cache.flush().unwrap();
+        assert_eq!(cache.dirty_len(), 0);
+        let writes = cache.store().writes;
+        cache.flush().unwrap();
+        assert_eq!(cache.store().writes, writes);
+
+        // Removal is written through
+        assert_eq!(cache.remove(&1), Ok(Some(10)));
+        assert!(!cache.store().data.contains_key(&1));
+        assert_eq!(cache.get(&1), Ok(None));
+    }
+
+    #[test]
+    fn failed_writes() {
+        let mut cache = WriteBack::new(SimplifiedTwoQ::with_threshold(2, 1), Store::default());
+        cache.put(1, 1).unwrap();
+        cache.put(2, 2).unwrap();
+        cache.get(&2).unwrap();
+        cache.store_mut().fail = true;
+        assert_eq!(cache.put(3, 3), Err("store unavailable".to_string()));
+        // The evicted value is kept until it is written
+        assert!(!cache.cache().contains(&1));
+        assert!(cache.is_dirty(&1));
+        assert_eq!(cache.get(&1), Ok(Some(Loaded::Cached(&1))));
+        assert_eq!(cache.dirty_len(), 3);
+        assert!(cache.flush().is_err());
+
+        cache.store_mut().fail = false;
+        cache.flush().unwrap();
+        assert_eq!(cache.dirty_len(), 0);
+        assert_eq!(cache.store().writes, 3);
+        assert_eq!(cache.store().data.len(), 3);
+    }
+
+    #[test]
+    fn flush_on_drop() {
+        let mut store = HashMap::new();
+        {
+            let mut cache = WriteBack::new(LfuCache::new(10), &mut store);
+            for i in 0..5u64 {
+                cache.put(i, i * 2).unwrap();
+            }
+        }
+        assert_eq!(store.len(), 5);
+        assert_eq!(store[&4], 8);
+
+        // A failed write does not stop the others
+        let mut store = Store {
+            broken: Some(0),
+            ..Store::default()
+        };
+        {
+            let mut cache = WriteBack::new(LfuCache::new(10), &mut store);
+            for i in 0..5u64 {
+                cache.put(i, i * 2).unwrap();
+            }
+            assert!(cache.flush_key(&0).is_err());
+        }
+        assert_eq!(store.data.len(), 4);
+
+        // A cache that keeps nothing writes through
+        let mut cache = WriteBack::new(LfuCache::new(0), Store::default());
+        cache.put(1, 1).unwrap();
+        cache.flush_key(&1).unwrap();
+        assert_eq!(cache.get(&1), Ok(Some(Loaded::Uncached(1))));
+        assert_eq!(cache.dirty_len(), 0);
+    }
+}
+mod buffer_pool {
+    use crate::helpers::temp_file;
+    use papers_web_love::buffer_pool::BufferPool;
+    use std::fs::{self, OpenOptions};
+    use std::io::ErrorKind;
+
+    const PAGE: usize = 64;
+
+    #[test]
+    fn persistence() {
+        let (path, file) = temp_file("pool-persistence");
+        let pool = BufferPool::new(file, PAGE, 4).unwrap();
+        for i in 0..10u8 {
+            let mut page = pool.new_page().unwrap();
+            assert_eq!(page.id(), i as u64);
+            page.data_mut().fill(i);
+        }
+        assert_eq!(pool.pages(), 10);
+        assert_eq!(pool.resident(), 4);
+        drop(pool);
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 10 * PAGE);
+        assert!(bytes
+            .chunks(PAGE)
+            .enumerate()
+            .all(|(i, p)| p.iter().all(|&b| b == i as u8)));
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let pool = BufferPool::new(file, PAGE, 4).unwrap();
+        assert_eq!(pool.pages(), 10);
+        assert!(pool.fetch(3).unwrap().data().iter().all(|&b| b == 3));
+        // Past the end of the file
+        assert!(pool.fetch(12).unwrap().data().iter().all(|&b| b == 0));
+        assert_eq!(pool.pages(), 13);
+        drop(pool);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pinning() {
+        let (path, file) = temp_file("pool-pinning");
+        let pool = BufferPool::new(file, PAGE, 2).unwrap();
+        let p0 = pool.fetch(0).unwrap();
+        let p1 = pool.fetch(1).unwrap();
+        let again = pool.fetch(1).unwrap();
+        assert_eq!(pool.pinned(), 2);
+        assert!(pool.fetch(2).is_err());
+        drop(p1);
+        assert!(pool.fetch(2).is_err());
+        drop(again);
+        assert_eq!(pool.fetch(2).unwrap().id(), 2);
+        assert!(pool.is_resident(0) && !pool.is_resident(1));
+        drop(p0);
+        assert_eq!(pool.pinned(), 0);
+        drop(pool);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_back() {
+        let (path, file) = temp_file("pool-write-back");
+        let pool = BufferPool::new(file, PAGE, 2).unwrap();
+        {
+            let mut p0 = pool.fetch(0).unwrap();
+            p0.data_mut()[..3].copy_from_slice(b"abc");
+            assert!(p0.is_dirty());
+        }
+        pool.fetch(1).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        // Page 0 is the victim and is written before its frame is reused
+        pool.fetch(2).unwrap();
+        assert!(!pool.is_resident(0));
+        assert_eq!(&fs::read(&path).unwrap()[..3], b"abc");
+        assert_eq!(&pool.fetch(0).unwrap().data()[..3], b"abc");
+
+        let mut p1 = pool.fetch(1).unwrap();
+        p1.data_mut()[0] = b'x';
+        pool.flush_page(1).unwrap();
+        assert!(!p1.is_dirty());
+        assert_eq!(fs::read(&path).unwrap()[PAGE], b'x');
+        drop(p1);
+
+        // A page being written is skipped by flushes and stays dirty
+        let mut page = pool.new_page().unwrap();
+        let id = page.id();
+        let mut data = page.data_mut();
+        data[0] = b'y';
+        let mut p0 = pool.fetch(0).unwrap();
+        p0.data_mut()[0] = b'z';
+        let e = pool.flush_all().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+        assert!(!p0.is_dirty());
+        assert_eq!(fs::read(&path).unwrap()[0], b'z');
+        drop(p0);
+        let e = pool.flush_page(id).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+        drop(data);
+        assert!(page.is_dirty());
+        pool.flush_page(id).unwrap();
+        assert!(!page.is_dirty());
+        drop(page);
+        drop(pool);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn scan_resistance() {
+        let (path, file) = temp_file("pool-scan");
+        let pool = BufferPool::new(file, PAGE, 4).unwrap();
+        // Page 0 is hot, pages 1.. are read once by a scan
+        pool.fetch(0).unwrap();
+        pool.fetch(0).unwrap();
+        for i in 1..100 {
+            pool.fetch(i).unwrap();
+            assert!(pool.is_resident(0));
+        }
+        drop(pool);
+        fs::remove_file(&path).unwrap();
+    }
+}
+mod tagged {
+    use papers_web_love::caching::lfu::LfuCache;
+    use papers_web_love::caching::tagged::TaggedCache;
+    use papers_web_love::caching::Cache;
+
+    #[test]
+    fn invalidate_tag() {
+        let mut cache = TaggedCache::new(LfuCache::new(10));
+        for i in 0..6 {
+            let tenant = if i % 2 == 0 { "even" } else { "odd" };
+            cache.insert_tagged(i, i, vec![tenant, "all", tenant]);
+        }
+        cache.insert(6, 6);
+        assert_eq!(cache.tags(&0), &["even", "all"]);
+        assert_eq!(cache.tag_len(&"all"), 6);
+        assert_eq!(cache.invalidate_tag(&"even"), 3);
+        assert_eq!(cache.len(), 4);
+        assert!(!cache.contains(&2) && cache.contains(&3));
+        assert_eq!(cache.tag_len(&"all"), 3);
+        assert_eq!(cache.invalidate_tag(&"even"), 0);
+
+        // Replacing an entry replaces its tags
+        cache.insert_tagged(1, 10, vec!["even"]);
+        assert_eq!(cache.tag_len(&"odd"), 2);
+        assert_eq!(cache.remove(&3), Some(3));
+        assert_eq!(cache.tag_len(&"odd"), 1);
+        assert_eq!(cache.invalidate_tag(&"all"), 1);
+        assert_eq!(cache.tag_len(&"odd"), 0);
+        assert_eq!(cache.get(&1), Some(&10));
+        assert!(cache.contains(&6));
+    }
+
+    #[test]
+    fn eviction() {
+        let mut cache = TaggedCache::new(LfuCache::new(3));
+        for i in 0..100 {
+            cache.insert_tagged(i, i, vec![i % 4]);
+            cache.get(&0);
+        }
+        // Evicted entries leave the index
+        let tagged: usize = (0..4).map(|t| cache.tag_len(&t)).sum();
+        assert_eq!(tagged, 3);
+        assert_eq!(cache.tag_len(&0), 1);
+        assert_eq!(cache.invalidate_tag(&3), 1);
+        assert_eq!(cache.len(), 2);
+
+        let mut empty = TaggedCache::new(LfuCache::new(0));
+        empty.insert_tagged(1, 1, vec!["a"]);
+        assert_eq!(empty.tag_len(&"a"), 0);
+    }
+}
+mod negative {
+    use papers_web_love::caching::lfu::LfuCache;
+    use papers_web_love::caching::negative::{Lookup, NegativeCache};
+    use papers_web_love::caching::Loaded;
+    use std::cell::Cell;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn lookup() {
+        let ttl = Duration::from_millis(50);
+        let mut cache = NegativeCache::new(LfuCache::new(10), 2, ttl);
+        assert_eq!(cache.get(&1), Lookup::Miss);
+        cache.insert(1, "one");
+        cache.insert_absent(2);
+        assert_eq!(cache.get(&1), Lookup::Present(&"one"));
+        assert_eq!(cache.get(&2), Lookup::Absent);
+
+        // Absences have their own capacity
+        cache.insert_absent(3);
+        cache.insert_absent(4);
+        assert_eq!(cache.absent_len(), 2);
+        assert_eq!(cache.get(&2), Lookup::Miss);
+        assert_eq!(cache.cache().len(), 1);
+
+        cache.insert_absent(1);
+        assert_eq!(cache.get(&1), Lookup::Absent);
+        cache.insert(1, "uno");
+        assert_eq!(cache.get(&1), Lookup::Present(&"uno"));
+        assert_eq!(cache.remove(&4), None);
+        assert_eq!(cache.get(&4), Lookup::Miss);
+
+        thread::sleep(ttl + ttl / 2);
+        assert_eq!(cache.get(&3), Lookup::Miss);
+        assert_eq!(cache.get(&1), Lookup::Present(&"uno"));
+    }
+
+    #[test]
+    fn loader() {
+        let loads = Cell::new(0);
+        let load = |k: &u32| -> Result<Option<u32>, String> {
+            loads.set(loads.get() + 1);
+            match k {
+                0 => Err("unavailable".to_string()),
+                k if k % 2 == 0 => Ok(Some(k * 10)),
+                _ => Ok(None),
+            }
+        };
+        let mut cache = NegativeCache::new(LfuCache::new(10), 10, Duration::from_secs(60));
+        for _ in 0..3 {
+            assert_eq!(cache.get_or_load(&2, &load), Ok(Some(Loaded::Cached(&20))));
+            assert_eq!(cache.get_or_load(&3, &load), Ok(None));
+        }
+        assert_eq!(loads.get(), 2);
+        assert!(cache.get_or_load(&0, &load).is_err());
+        assert!(cache.get_or_load(&0, &load).is_err());
+        assert_eq!(loads.get(), 4);
+        assert_eq!(cache.get(&3), Lookup::Absent);
+        cache.clear();
+        assert_eq!(cache.get(&3), Lookup::Miss);
+    }
+}
+mod tiered {
+    use crate::helpers::temp_path;
+    use papers_web_love::caching::admission::SeenTwice;
+    use papers_web_love::caching::lfu::LfuCache;
+    use papers_web_love::caching::tiered::{DiskTier, Tiered};
+    use papers_web_love::caching::two_q_lru::SimplifiedTwoQ;
+    use std::fs;
+
+    #[test]
+    fn demote_promote() {
+        let dir = temp_path("tiered-demote");
+        let disk = DiskTier::open(&dir, LfuCache::new(3)).unwrap();
+        let mut cache = Tiered::new(SimplifiedTwoQ::with_capacity(2), disk);
+        for i in 0..5u64 {
+            cache.insert(i, format!("value {}", i)).unwrap();
+        }
+        // Memory A1 [4,3], disk [2,1,0]
+        assert_eq!(cache.len(), 5);
+        assert_eq!(cache.disk().len(), 3);
+        assert_eq!(
+            cache.get(&0).unwrap().as_deref(),
+            Some(&"value 0".to_string())
+        );
+        assert!(cache.memory().contains(&0) && !cache.disk().contains(&0));
+        assert!(cache.disk().contains(&3));
+        // 4 is demoted and the disk evicts 1
+        cache.insert(5, "value 5".to_string()).unwrap();
+        assert!(!cache.contains(&1));
+        assert_eq!(cache.len(), 5);
+        assert_eq!(cache.remove(&2).unwrap(), Some("value 2".to_string()));
+        assert_eq!(cache.get(&2).unwrap(), None);
+        // 0 is demoted
+        cache.insert(3, "new 3".to_string()).unwrap();
+        assert!(!cache.disk().contains(&3) && cache.disk().contains(&0));
+        let files = fs::read_dir(&dir).unwrap().count();
+        assert_eq!(files, cache.disk().len());
+        drop(cache);
+
+        // The disk tier survives a restart, damaged entries are dropped
+        fs::write(dir.join("entry.1000"), b"garbage").unwrap();
+        let mut disk = DiskTier::<u64, String, _>::open(&dir, LfuCache::new(3)).unwrap();
+        assert_eq!(disk.len(), 2);
+        assert!(!dir.join("entry.1000").exists());
+        assert_eq!(disk.get(&4).unwrap(), Some("value 4".to_string()));
+        disk.clear().unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejection() {
+        let dir = temp_path("tiered-rejection");
+        let policy = LfuCache::new(1).with_admission(SeenTwice::new(100));
+        let disk = DiskTier::open(&dir, policy).unwrap();
+        let memory = LfuCache::new(1).with_admission(SeenTwice::new(100));
+        let mut cache = Tiered::new(memory, disk);
+        assert!(cache.insert(0, "value 0".to_string()).unwrap());
+        // Memory rejects 1, it goes to disk
+        assert!(cache.insert(1, "value 1".to_string()).unwrap());
+        assert!(cache.disk().contains(&1));
+        // Both tiers reject 2
+        assert!(!cache.insert(2, "value 2".to_string()).unwrap());
+        assert!(!cache.contains(&2));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(
+            cache.get(&1).unwrap().as_deref(),
+            Some(&"value 1".to_string())
+        );
+        drop(cache);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+mod segments {
+    use crate::helpers::temp_path;
+    use papers_web_love::caching::lfu::LfuCache;
+    use papers_web_love::caching::segments::{SegmentConfig, SegmentEviction, SegmentStore};
+    use papers_web_love::caching::writeback::WriteBack;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    // Records of these values take 29 bytes, two of them fit in a segment
+    fn config(eviction: SegmentEviction) -> SegmentConfig {
+        SegmentConfig {
+            segment_size: 64,
+            segments: 3,
+            eviction,
+        }
+    }
+
+    #[test]
+    fn fifo() {
+        let dir = temp_path("segments-fifo");
+        let mut store = SegmentStore::open(&dir, config(SegmentEviction::Fifo)).unwrap();
+        for i in 0..20u64 {
+            store.put(i, &format!("value {}", i)).unwrap();
+        }
+        assert_eq!(store.segments(), 3);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        assert_eq!(store.len(), 6);
+        assert!(!store.contains(&13) && store.contains(&14));
+        assert_eq!(store.get(&19).unwrap(), Some("value 19".to_string()));
+        assert_eq!(store.get(&0).unwrap(), None);
+        assert!(store.delete(&19).unwrap());
+        assert!(!store.delete(&19).unwrap());
+        // The delete record starts a new segment, 14 and 15 are evicted
+        assert!(!store.contains(&14));
+        store.put(15, &"new 15".to_string()).unwrap();
+        store.sync().unwrap();
+        drop(store);
+
+        // A torn record at the end is dropped
+        let mut last = (0..100).rev().map(|i| dir.join(format!("segment.{}", i)));
+        let last = last.find(|p| p.exists()).unwrap();
+        let len = fs::metadata(&last).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&last).unwrap();
+        file.write_all(&[40, 0, 0, 0, 1, 2, 3]).unwrap();
+        drop(file);
+
+        let mut store =
+            SegmentStore::<u64, String>::open(&dir, config(SegmentEviction::Fifo)).unwrap();
+        assert_eq!(fs::metadata(&last).unwrap().len(), len);
+        assert_eq!(store.len(), 4);
+        assert!(!store.contains(&19));
+        assert_eq!(store.get(&15).unwrap(), Some("new 15".to_string()));
+        assert_eq!(store.get(&16).unwrap(), Some("value 16".to_string()));
+        store.put(20, &"value 20".to_string()).unwrap();
+        assert_eq!(store.get(&20).unwrap(), Some("value 20".to_string()));
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn probation() {
+        for &eviction in [SegmentEviction::Fifo, SegmentEviction::Probation].iter() {
+            let dir = temp_path("segments-probation");
+            let mut store = SegmentStore::open(&dir, config(eviction)).unwrap();
+            for i in 0..6u64 {
+                store.put(i, &format!("value {}", i)).unwrap();
+            }
+            assert_eq!(store.get(&0).unwrap(), Some("value 0".to_string()));
+            // The first segment is evicted, 0 was read
+            store.put(6, &"value 6".to_string()).unwrap();
+            assert!(!store.contains(&1));
+            let kept = eviction == SegmentEviction::Probation;
+            assert_eq!(store.contains(&0), kept);
+            if kept {
+                // 0 starts a new probation
+                for i in 7..11u64 {
+                    store.put(i, &format!("value {}", i)).unwrap();
+                }
+                assert_eq!(store.get(&0).unwrap(), Some("value 0".to_string()));
+                store.put(11, &"value 11".to_string()).unwrap();
+                assert!(store.contains(&0) && !store.contains(&6));
+            }
+            drop(store);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn reopen_fewer_segments() {
+        let dir = temp_path("segments-reopen");
+        let mut store = SegmentStore::open(&dir, config(SegmentEviction::Probation)).unwrap();
+        for i in 0..6u64 {
+            store.put(i, &format!("value {}", i)).unwrap();
+        }
+        drop(store);
+
+        let config = SegmentConfig {
+            segments: 1,
+            ..config(SegmentEviction::Probation)
+        };
+        let mut store = SegmentStore::<u64, String>::open(&dir, config).unwrap();
+        assert_eq!(store.segments(), 1);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(store.len(), 2);
+        assert!(!store.contains(&3) && store.contains(&4));
+        assert_eq!(store.get(&5).unwrap(), Some("value 5".to_string()));
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backing_store() {
+        let dir = temp_path("segments-backing");
+        let store = SegmentStore::open(&dir, SegmentConfig::default()).unwrap();
+        let mut cache = WriteBack::new(LfuCache::new(2), store);
+        for i in 0..10u64 {
+            cache.put(i, i * 10).unwrap();
+        }
+        cache.flush().unwrap();
+        assert_eq!(cache.store().len(), 10);
+        assert_eq!(cache.get(&3).unwrap().as_deref(), Some(&30));
+        assert_eq!(cache.remove(&3).unwrap(), Some(30));
+        assert!(!cache.store().contains(&3));
+        drop(cache);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+mod admission {
+    use papers_web_love::caching::admission::{AdmissionPolicy, SeenTwice, TinyLfu};
+    use papers_web_love::caching::gdsf::GdsfCache;
+    use papers_web_love::caching::lfu::LfuCache;
+    use papers_web_love::caching::mq::MultiQueue;
+    use papers_web_love::caching::two_q_lru::SimplifiedTwoQ;
+    use papers_web_love::caching::{Cache, Insertion, Loaded};
+
+    // Hot keys are read between the keys of a long scan,
+    // returns how many hot keys are cached after the scan
+    fn scan<C: Cache<u64, ()>>(cache: &mut C) -> usize {
+        for i in 0..20_000u64 {
+            for k in [i % 50, 1_000_000 + i].iter() {
+                if cache.get(k).is_none() {
+                    cache.insert(*k, ());
+                }
+            }
+        }
+        (0..50).filter(|k| cache.contains(k)).count()
+    }
+
+    #[test]
+    fn tiny_lfu() {
+        let mut admission = TinyLfu::new(100);
+        for _ in 0..5 {
+            admission.record(&1);
+        }
+        admission.record(&2);
+        assert_eq!(admission.frequency(&1), 5);
+        assert!(admission.admit(&1, &2) && !admission.admit(&2, &1));
+        assert!(!admission.admit(&3, &3));
+
+        let mut cache = SimplifiedTwoQ::with_capacity(60).with_admission(TinyLfu::new(60));
+        // Am is too small for every hot key, but the scan stays out of it
+        let hot = scan(&mut cache);
+        assert!(hot >= 40 && hot > scan(&mut SimplifiedTwoQ::with_capacity(60)));
+        let mut cache = LfuCache::new(60).with_admission(TinyLfu::new(60));
+        assert_eq!(scan(&mut cache), 50);
+    }
+
+    #[test]
+    fn seen_twice() {
+        let mut cache = LfuCache::new(2).with_admission(SeenTwice::new(100));
+        cache.insert(0, 0);
+        cache.insert(1, 1);
+        cache.get(&0);
+        // The first insert of 2 is rejected, the second one evicts 1
+        assert_eq!(cache.try_insert(2, 2).unwrap(), Insertion::Rejected(2, 2));
+        assert_eq!(cache.try_insert(2, 2).unwrap(), Insertion::Evicted(1, 1));
+        assert!(cache.contains(&0) && cache.contains(&2));
+
+        let mut cache = SimplifiedTwoQ::with_capacity(60).with_admission(SeenTwice::new(60));
+        assert!(scan(&mut cache) > scan(&mut SimplifiedTwoQ::with_capacity(60)));
+    }
+
+    #[test]
+    fn insert_loaded() {
+        let mut cache = LfuCache::new(1).with_admission(SeenTwice::new(100));
+        assert_eq!(cache.insert_loaded(0, 0), (Loaded::Cached(&0), None));
+        // The read-through wrappers hand the rejected value to the caller
+        assert_eq!(cache.insert_loaded(1, 1), (Loaded::Uncached(1), None));
+        assert_eq!(
+            cache.insert_loaded(1, 2),
+            (Loaded::Cached(&2), Some((0, 0)))
+        );
+
+        cache.pin(&1);
+        assert_eq!(cache.insert_loaded(3, 3), (Loaded::Uncached(3), None));
+        let mut cache = MultiQueue::with_capacity(0);
+        assert_eq!(cache.insert_loaded(4, 4), (Loaded::Uncached(4), None));
+        let mut cache = GdsfCache::new(0);
+        assert_eq!(cache.insert_loaded(5, 5).0.into_owned(), 5);
+        assert!(cache.is_empty());
+    }
+}
+
+mod sketch {
+    use papers_web_love::common::sketch::CountMinSketch;
+
+    #[test]
+    fn error_target() {
+        let sketch = CountMinSketch::with_error(0.01, 0.01);
+        assert_eq!((sketch.width(), sketch.depth()), (272, 5));
+
+        let mut sketch = CountMinSketch::with_error(0.01, 0.01).seed(7);
+        for i in 0..1000u32 {
+            for _ in 0..i % 4 {
+                sketch.increment(&i);
+            }
+        }
+        // Never an underestimate, rarely off by more than epsilon * 1500
+        let mut off = 0;
+        for i in 0..1000u32 {
+            let estimate = sketch.estimate(&i);
+            assert!(estimate >= (i % 4) as u8);
+            if estimate > (i % 4) as u8 + 15 {
+                off += 1;
+            }
+        }
+        assert!(off <= 10);
+    }
+
+    #[test]
+    fn saturation_and_halving() {
+        let mut sketch = CountMinSketch::new(64, 4);
+        for _ in 0..100 {
+            sketch.increment("hot");
+        }
+        assert_eq!(sketch.estimate("hot"), 15);
+        sketch.halve();
+        assert_eq!(sketch.estimate("hot"), 7);
+        sketch.clear();
+        assert_eq!(sketch.estimate("hot"), 0);
+
+        // Halved after every 8 increments
+        let mut sketch = CountMinSketch::new(64, 4).halve_every(8);
+        for _ in 0..7 {
+            sketch.increment(&1);
+        }
+        assert_eq!(sketch.estimate(&1), 7);
+        sketch.increment(&1);
+        assert_eq!(sketch.estimate(&1), 4);
+    }
+
+    #[test]
+    fn conservative_update() {
+        // A single row of 16 counters, so most keys share counters
+        let mut sketch = CountMinSketch::new(16, 2);
+        for i in 0..8u32 {
+            sketch.increment(&i);
+        }
+        let total: u32 = (0..8u32).map(|i| sketch.estimate(&i) as u32).sum();
+        assert!(total >= 8);
+        // Repeating a key only raises its own minimum
+        let before = sketch.estimate(&0);
+        sketch.increment(&0);
+        assert_eq!(sketch.estimate(&0), before + 1);
+    }
+}
+
+mod bloom {
+    use papers_web_love::common::bloom::{BloomFilter, CountingBloomFilter};
+
+    #[test]
+    fn false_positive_rate() {
+        let mut filter = BloomFilter::new(1000, 0.01).seed(42);
+        for i in 0..1000u32 {
+            filter.insert(&i);
+        }
+        assert!((0..1000u32).all(|i| filter.contains(&i)));
+        let fp = (1000..11_000u32).filter(|i| filter.contains(i)).count();
+        assert!(fp < 200, "{} false positives", fp);
+        assert!(filter.len() > 990 && filter.len() <= 1000);
+        assert!(!filter.insert(&0));
+        filter.clear();
+        assert!(filter.is_empty() && !filter.contains(&0));
+    }
+
+    #[test]
+    fn seeds() {
+        let mut a = BloomFilter::new(10, 0.5).seed(1);
+        let mut b = BloomFilter::new(10, 0.5).seed(2);
+        for i in 0..10u32 {
+            a.insert(&i);
+            b.insert(&i);
+        }
+        // Keys colliding in one filter rarely collide in the other
+        let both = (10..10_000u32)
+            .filter(|i| a.contains(i) && b.contains(i))
+            .count();
+        let one = (10..10_000u32).filter(|i| a.contains(i)).count();
+        assert!(both < one);
+    }
+
+    #[test]
+    fn counting() {
+        let mut filter = CountingBloomFilter::new(100, 0.01);
+        filter.insert("a");
+        filter.insert("a");
+        filter.insert("b");
+        assert_eq!(filter.len(), 3);
+        assert!(filter.count("a") >= 2 && filter.count("b") >= 1);
+        assert!(filter.remove("a") && filter.contains("a"));
+        assert!(filter.remove("a") && !filter.contains("a"));
+        assert!(!filter.remove("a"));
+        assert!(filter.contains("b"));
+        assert_eq!(filter.len(), 1);
+        filter.clear();
+        assert!(filter.is_empty() && !filter.contains("b"));
+
+        // Saturated counters never go down
+        for _ in 0..300 {
+            filter.insert("c");
+        }
+        assert_eq!(filter.count("c"), 255);
+        for _ in 0..301 {
+            assert!(filter.remove("c"));
+        }
+        assert!(filter.contains("c") && filter.is_empty());
+    }
 }